@@ -0,0 +1,2813 @@
+//! Core Rice setup and health-check logic, shared by the `rice-cli` binary
+//! and Rust programs that embed it.
+//!
+//! Nothing here prompts or prints, so callers decide how to present results.
+//! Diagnostics go through the [`log`] facade at debug level. The `cli`
+//! feature (on by default) builds the binary and derives its clap impls;
+//! embedders can turn it off with `default-features = false`:
+//!
+//! ```no_run
+//! use rice_cli::{RiceConfig, RiceSetup};
+//!
+//! let mut config = RiceConfig::default();
+//! config.storage.instance_url = "rice.internal:50051".into();
+//! RiceSetup::new(config).apply()?;
+//! # Ok::<(), rice_cli::RiceError>(())
+//! ```
+
+use reqwest::{Client, Response, StatusCode};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Why a Rice operation failed. [`RiceError::exit_code`] gives the exit
+/// code the CLI uses for it.
+#[derive(Debug, thiserror::Error)]
+pub enum RiceError {
+    /// Rice configuration is missing or invalid
+    #[error("{0}")]
+    Config(String),
+    /// A config file or bundle that could not be parsed
+    #[error("{0}")]
+    Parse(String),
+    /// A value that failed validation, such as a malformed URL or port
+    #[error("{0}")]
+    Validation(String),
+    /// Rice could not be reached (connection refused, timeout, DNS)
+    #[error("{0}")]
+    Unreachable(String),
+    /// Rice answered but is unhealthy
+    #[error("{0}")]
+    Unhealthy(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    #[cfg(feature = "cli")]
+    Prompt(#[from] dialoguer::Error),
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl RiceError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RiceError::Unreachable(_) => 3,
+            RiceError::Unhealthy(_) => 4,
+            RiceError::Config(_) | RiceError::Parse(_) | RiceError::Validation(_) => 5,
+            _ => 1,
+        }
+    }
+}
+
+/// A [`RiceError::Validation`] with `message`.
+fn invalid(message: impl Into<String>) -> RiceError {
+    RiceError::Validation(message.into())
+}
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// Redirects a health request follows before giving up.
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// Oldest Rice server version this CLI and the SDK it sets up support.
+pub const MIN_SERVER_VERSION: &str = "0.1.0";
+
+/// Machine-specific overrides layered over .env, as in Next.js and Vite.
+pub const LOCAL_ENV_FILE: &str = ".env.local";
+
+const RICE_ENV_HEADER: &str = "# Rice Configuration";
+
+/// Comment that records the `--env-prefix` a .env file was written with, so
+/// later commands read the same variable names.
+const ENV_PREFIX_MARKER: &str = "# rice-cli env-prefix:";
+
+pub const RICE_ENV_KEYS: [&str; 11] = [
+    "STORAGE_ENABLED",
+    "STORAGE_INSTANCE_URL",
+    "STORAGE_USER",
+    "STORAGE_AUTH_TOKEN",
+    "STORAGE_HTTP_PORT",
+    "STORAGE_HEALTH_PATH",
+    "STATE_ENABLED",
+    "STATE_INSTANCE_URL",
+    "STATE_AUTH_TOKEN",
+    "STATE_RUN_ID",
+    "STATE_HTTP_PORT",
+];
+
+/// Placeholder value and comment for each Rice key in `.env.example`.
+/// Tokens stay empty so the template never holds a secret.
+const ENV_EXAMPLE_ENTRIES: [(&str, &str, &str); 11] = [
+    ("STORAGE_ENABLED", "true", "Use Rice Storage (true/false)"),
+    (
+        "STORAGE_INSTANCE_URL",
+        "localhost:50051",
+        "Storage address: host:port or an http(s):// URL",
+    ),
+    ("STORAGE_USER", "admin", "Storage user"),
+    (
+        "STORAGE_AUTH_TOKEN",
+        "",
+        "Storage auth token (ask your team, never commit it)",
+    ),
+    (
+        "STORAGE_HTTP_PORT",
+        "3000",
+        "HTTP port of the Storage health endpoint",
+    ),
+    (
+        "STORAGE_HEALTH_PATH",
+        "/health",
+        "Path of the Storage health endpoint",
+    ),
+    ("STATE_ENABLED", "false", "Use Rice State (true/false)"),
+    (
+        "STATE_INSTANCE_URL",
+        "localhost:50051",
+        "State gRPC address: host:port",
+    ),
+    (
+        "STATE_AUTH_TOKEN",
+        "",
+        "State auth token (ask your team, never commit it)",
+    ),
+    (
+        "STATE_RUN_ID",
+        "default",
+        "Run ID that State data is scoped to",
+    ),
+    (
+        "STATE_HTTP_PORT",
+        "",
+        "HTTP port of the State health endpoint (empty: check the gRPC port)",
+    ),
+];
+
+/// Rice keys whose values are redacted unless explicitly revealed.
+pub const SECRET_ENV_KEYS: [&str; 2] = ["STORAGE_AUTH_TOKEN", "STATE_AUTH_TOKEN"];
+
+/// Why a health request failed, told apart so each case can get its own
+/// hint.
+pub enum RequestFailure {
+    Timeout,
+    Refused,
+    Dns,
+    Tls,
+    Redirects,
+    Other,
+}
+
+impl RequestFailure {
+    pub fn classify(error: &reqwest::Error) -> RequestFailure {
+        if error.is_timeout() {
+            return RequestFailure::Timeout;
+        }
+        if error.is_redirect() {
+            return RequestFailure::Redirects;
+        }
+        let mut tcp_failed = false;
+        let mut source: Option<&dyn std::error::Error> = Some(error);
+        while let Some(e) = source {
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+            {
+                return RequestFailure::Refused;
+            }
+            // hyper-util's resolver and connector errors are private types.
+            let text = e.to_string();
+            if text == "dns error" {
+                return RequestFailure::Dns;
+            }
+            tcp_failed |= text == "tcp connect error";
+            source = e.source();
+        }
+        // Past DNS and TCP, a failed HTTPS connect is the TLS handshake.
+        let https = error.url().is_some_and(|url| url.scheme() == "https");
+        if https && error.is_connect() && !tcp_failed {
+            RequestFailure::Tls
+        } else {
+            RequestFailure::Other
+        }
+    }
+}
+
+/// The innermost error in `error`'s source chain, which names the actual
+/// failure (e.g. "Connection reset by peer").
+pub fn root_cause(error: &dyn std::error::Error) -> String {
+    let mut error = error;
+    while let Some(source) = error.source() {
+        error = source;
+    }
+    error.to_string()
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+/// The package.json nearest to `path`, searching its directory and then
+/// each parent, as Node does.
+pub fn find_package_json(path: &Path) -> Option<PathBuf> {
+    std::path::absolute(path)
+        .ok()?
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("package.json"))
+        .find(|path| path.is_file())
+}
+
+/// Node package manager, for the SDK install hint.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Npm,
+    Pnpm,
+    Yarn,
+    Bun,
+}
+
+impl PackageManager {
+    pub const ALL: [PackageManager; 4] = [
+        PackageManager::Npm,
+        PackageManager::Pnpm,
+        PackageManager::Yarn,
+        PackageManager::Bun,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PackageManager::Npm => "npm",
+            PackageManager::Pnpm => "pnpm",
+            PackageManager::Yarn => "yarn",
+            PackageManager::Bun => "bun",
+        }
+    }
+
+    pub fn lockfiles(self) -> &'static [&'static str] {
+        match self {
+            PackageManager::Npm => &["package-lock.json"],
+            PackageManager::Pnpm => &["pnpm-lock.yaml"],
+            PackageManager::Yarn => &["yarn.lock"],
+            PackageManager::Bun => &["bun.lockb", "bun.lock"],
+        }
+    }
+
+    pub fn install_command(self) -> &'static str {
+        match self {
+            PackageManager::Npm => "npm install rice-node-sdk",
+            PackageManager::Pnpm => "pnpm add rice-node-sdk",
+            PackageManager::Yarn => "yarn add rice-node-sdk",
+            PackageManager::Bun => "bun add rice-node-sdk",
+        }
+    }
+
+    /// The package managers whose lockfiles sit next to `package_json`, or
+    /// in the nearest parent that has any (a workspace root). Falls back to
+    /// the `packageManager` field; empty when nothing says which is used.
+    pub fn detect(package_json: &Path) -> Vec<PackageManager> {
+        let found = package_json
+            .ancestors()
+            .skip(1)
+            .map(|dir| {
+                Self::ALL
+                    .into_iter()
+                    .filter(|manager| {
+                        manager
+                            .lockfiles()
+                            .iter()
+                            .any(|lockfile| dir.join(lockfile).is_file())
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .find(|managers| !managers.is_empty());
+        if let Some(managers) = found {
+            return managers;
+        }
+
+        let declared = fs::read_to_string(package_json)
+            .ok()
+            .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+            .and_then(|json| json["packageManager"].as_str().map(str::to_string));
+        Self::ALL
+            .into_iter()
+            .filter(|manager| {
+                declared
+                    .as_deref()
+                    .is_some_and(|declared| declared.split('@').next() == Some(manager.name()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ModuleFormat {
+    /// ES module (`export default`)
+    Esm,
+    /// CommonJS (`module.exports`)
+    Cjs,
+}
+
+impl ModuleFormat {
+    /// A `.cjs` or `.mjs` extension fixes the format. Otherwise uses the
+    /// `"type"` field of the package.json nearest to `config_path`, as Node
+    /// does, defaulting to CommonJS when no package.json is found.
+    pub fn detect(config_path: &Path) -> ModuleFormat {
+        match config_path.extension().and_then(|ext| ext.to_str()) {
+            Some("cjs") => return ModuleFormat::Cjs,
+            Some("mjs") => return ModuleFormat::Esm,
+            _ => {}
+        }
+        match find_package_json(config_path) {
+            Some(package_json) => Self::from_package_json(&package_json),
+            None => ModuleFormat::Cjs,
+        }
+    }
+
+    pub fn from_package_json(path: &Path) -> ModuleFormat {
+        let is_module = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+            .is_some_and(|json| json["type"] == "module");
+        if is_module {
+            ModuleFormat::Esm
+        } else {
+            ModuleFormat::Cjs
+        }
+    }
+
+    pub fn export_prefix(self) -> &'static str {
+        match self {
+            ModuleFormat::Esm => "export default",
+            ModuleFormat::Cjs => "module.exports =",
+        }
+    }
+}
+
+/// How setup writes the Rice variables into an existing .env.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EnvMode {
+    /// Add missing keys; keep the values of keys already in the file
+    Append,
+    /// Replace the whole file with only the Rice variables
+    Overwrite,
+    /// Update existing keys in place and add missing ones
+    #[default]
+    Merge,
+}
+
+impl EnvMode {
+    pub fn apply(self, existing: &str, prefix: &str, values: &[(&str, &str)]) -> String {
+        match self {
+            EnvMode::Append => {
+                let missing: Vec<_> = values
+                    .iter()
+                    .filter(|(key, _)| !env_has_key(existing, &format!("{}{}", prefix, key)))
+                    .copied()
+                    .collect();
+                merge_env(existing, prefix, &missing)
+            }
+            EnvMode::Overwrite => merge_env("", prefix, values),
+            EnvMode::Merge => merge_env(existing, prefix, values),
+        }
+    }
+}
+
+/// Preset prompt defaults for common deployments.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SetupTemplate {
+    /// Both services on localhost
+    Local,
+    /// Both services behind a Compose service named `rice`
+    DockerCompose,
+    /// A hosted instance over HTTPS (replace the example host)
+    Cloud,
+}
+
+impl SetupTemplate {
+    /// The template's default for a Rice variable, if it sets one. Local
+    /// and Compose instances run without auth, so their tokens are empty.
+    pub fn default_value(self, var: &str) -> Option<&'static str> {
+        let (storage_url, state_url) = match self {
+            SetupTemplate::Local => ("localhost:50051", "localhost:50051"),
+            SetupTemplate::DockerCompose => ("rice:50051", "rice:50051"),
+            SetupTemplate::Cloud => ("https://rice.example.com", "rice.example.com:443"),
+        };
+        match var {
+            "STORAGE_ENABLED" | "STATE_ENABLED" => Some("true"),
+            "STORAGE_INSTANCE_URL" => Some(storage_url),
+            "STORAGE_HTTP_PORT" => Some("3000"),
+            "STATE_INSTANCE_URL" => Some(state_url),
+            "STORAGE_AUTH_TOKEN" | "STATE_AUTH_TOKEN" if self != SetupTemplate::Cloud => Some(""),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ConfigFormat {
+    /// rice.config.js
+    #[default]
+    Js,
+    /// rice.config.json
+    Json,
+    /// rice.config.toml
+    Toml,
+}
+
+/// The config file names searched for, in order, when none is given.
+pub const CONFIG_FILE_NAMES: [&str; 5] = [
+    "rice.config.js",
+    "rice.config.cjs",
+    "rice.config.mjs",
+    "rice.config.json",
+    "rice.config.toml",
+];
+
+/// The directory of config fragments written by `setup --config-dir`, found
+/// when none of [`CONFIG_FILE_NAMES`] exists.
+pub const CONFIG_DIR: &str = "rice.config.d";
+
+/// The fragment files in a config directory, one per service, named after
+/// the section each one holds.
+const CONFIG_FRAGMENTS: [(&str, &str); 2] = [("storage", "storage.js"), ("state", "state.js")];
+
+/// Renders a config directory: `storage.js` and `state.js`, each exporting
+/// its section, and an `index.js` that merges them into the full config.
+/// Returns `(file name, content)` pairs.
+pub fn render_config_fragments(
+    module_format: ModuleFormat,
+    storage: bool,
+    state: bool,
+) -> Vec<(&'static str, String)> {
+    let mut files: Vec<(&str, String)> = CONFIG_FRAGMENTS
+        .into_iter()
+        .zip([storage, state])
+        .map(|((section, file), enabled)| {
+            let content = format!(
+                "/** @type {{import('rice-node-sdk').RiceConfig['{}']}} */\n{} {{\n  enabled: {},\n}};",
+                section,
+                module_format.export_prefix(),
+                enabled
+            );
+            (file, content)
+        })
+        .collect();
+    let index = match module_format {
+        ModuleFormat::Esm => {
+            "import storageConfig from './storage.js';\nimport stateConfig from './state.js';\n\n/** @type {import('rice-node-sdk').RiceConfig} */\nexport default {\n  storage: storageConfig,\n  state: stateConfig,\n};"
+        }
+        ModuleFormat::Cjs => {
+            "/** @type {import('rice-node-sdk').RiceConfig} */\nmodule.exports = {\n  storage: require('./storage.js'),\n  state: require('./state.js'),\n};"
+        }
+    };
+    files.push(("index.js", index.to_string()));
+    files
+}
+
+/// Validates a config file, or each fragment of a config directory. A
+/// fragment's problems name the file, with line numbers within it.
+pub fn validate_config_path(
+    path: &Path,
+    format: ConfigFormat,
+) -> Result<Result<(bool, bool), Vec<String>>, RiceError> {
+    if !path.is_dir() {
+        return Ok(validate_config(&fs::read_to_string(path)?, format));
+    }
+    let mut enabled = [false; 2];
+    let mut problems = Vec::new();
+    for (index, (section, file)) in CONFIG_FRAGMENTS.into_iter().enumerate() {
+        let file_path = path.join(file);
+        let content = fs::read_to_string(&file_path).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("{}: {}", file_path.display(), e))
+        })?;
+        match validate_config_fragment(&content, section) {
+            Ok(value) => enabled[index] = value,
+            Err(fragment_problems) => problems.extend(
+                fragment_problems
+                    .into_iter()
+                    .map(|problem| format!("{}: {}", file, problem)),
+            ),
+        }
+    }
+    if problems.is_empty() {
+        Ok(Ok((enabled[0], enabled[1])))
+    } else {
+        Ok(Err(problems))
+    }
+}
+
+/// Validates a fragment by placing its exported object under `section` of
+/// an otherwise complete config, starting on the line of its export so the
+/// line numbers still match the fragment.
+fn validate_config_fragment(content: &str, section: &str) -> Result<bool, Vec<String>> {
+    let Some((offset, export)) = ["module.exports", "export default"]
+        .into_iter()
+        .find_map(|export| content.find(export).map(|offset| (offset, export)))
+    else {
+        return Err(vec![
+            "no `module.exports` or `export default` found".to_string(),
+        ]);
+    };
+    let after = content[offset + export.len()..].trim_start();
+    let object = after.strip_prefix('=').unwrap_or(after).trim();
+    let object = object.trim_end_matches(';').trim_end();
+    let other = if section == "storage" {
+        "state"
+    } else {
+        "storage"
+    };
+    let wrapped = format!(
+        "{}module.exports = {{ {}: {}, {}: {{ enabled: false }} }};",
+        "\n".repeat(content[..offset].matches('\n').count()),
+        section,
+        object,
+        other
+    );
+    let (storage, state) = validate_config(&wrapped, ConfigFormat::Js)?;
+    Ok(if section == "storage" { storage } else { state })
+}
+
+/// Removes the files setup writes to a config directory, then the directory
+/// itself unless other files remain. Returns whether it was removed.
+pub fn remove_config_dir(dir: &Path) -> Result<bool, RiceError> {
+    for file in CONFIG_FRAGMENTS
+        .map(|(_, file)| file)
+        .into_iter()
+        .chain(["index.js"])
+    {
+        match fs::remove_file(dir.join(file)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    match fs::remove_dir(dir) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks a `--config-name`: a bare file name, created in the current
+/// directory.
+pub fn validate_config_name(name: &str) -> Result<(), RiceError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(invalid("the config name must be a file name"));
+    }
+    if name.contains(['/', '\\']) {
+        return Err(invalid(format!(
+            "'{}' is a path; use --config-file for a config file outside the current directory",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl ConfigFormat {
+    pub const ALL: [ConfigFormat; 3] = [ConfigFormat::Js, ConfigFormat::Json, ConfigFormat::Toml];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ConfigFormat::Js => "rice.config.js",
+            ConfigFormat::Json => "rice.config.json",
+            ConfigFormat::Toml => "rice.config.toml",
+        }
+    }
+
+    /// Infers the format from a file extension, defaulting to JavaScript
+    /// (which covers `.cjs` and `.mjs`).
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Js,
+        }
+    }
+
+    /// Which of the Rice variable `values` go in `.env` next to a config file
+    /// of this format: all of them, or next to a TOML file, which holds the
+    /// rest, only the tokens that are set.
+    pub fn env_file_values<'k, 'v>(self, values: &[(&'k str, &'v str)]) -> Vec<(&'k str, &'v str)> {
+        values
+            .iter()
+            .copied()
+            .filter(|(var, value)| {
+                self != ConfigFormat::Toml || (SECRET_ENV_KEYS.contains(var) && !value.is_empty())
+            })
+            .collect()
+    }
+
+    /// Renders the config file. Only TOML also holds the connection
+    /// `values`, under `[rice.storage]` and `[rice.state]`; empty values
+    /// are left out, and so are the auth tokens, which stay in `.env`.
+    pub fn render(
+        self,
+        module_format: ModuleFormat,
+        storage: bool,
+        state: bool,
+        values: &[(&str, &str)],
+    ) -> String {
+        match self {
+            ConfigFormat::Js => format!(
+                "/** @type {{import('rice-node-sdk').RiceConfig}} */\n{} {{\n  storage: {{\n    enabled: {},\n  }},\n  state: {{\n    enabled: {},\n  }},\n}};",
+                module_format.export_prefix(),
+                storage,
+                state
+            ),
+            ConfigFormat::Json => format!(
+                "{{\n  \"storage\": {{\n    \"enabled\": {}\n  }},\n  \"state\": {{\n    \"enabled\": {}\n  }}\n}}\n",
+                storage, state
+            ),
+            ConfigFormat::Toml => {
+                let mut content = String::new();
+                for (section, enabled) in [("storage", storage), ("state", state)] {
+                    if !content.is_empty() {
+                        content.push('\n');
+                    }
+                    content.push_str(&format!("[rice.{}]\nenabled = {}\n", section, enabled));
+                    for (var, _, field) in
+                        BUNDLE_FIELDS
+                            .into_iter()
+                            .filter(|&(var, field_section, _)| {
+                                field_section == section && !SECRET_ENV_KEYS.contains(&var)
+                            })
+                    {
+                        match values.iter().find(|(key, _)| *key == var) {
+                            Some((_, value)) if !value.is_empty() => content.push_str(&format!(
+                                "{} = {}\n",
+                                field,
+                                toml::Value::String(value.to_string())
+                            )),
+                            _ => {}
+                        }
+                    }
+                }
+                content
+            }
+        }
+    }
+}
+
+/// Run IDs name State sessions, so they are limited to 1-64 of
+/// `A-Z a-z 0-9 . _ -`. Surrounding whitespace is ignored (and trimmed
+/// before the ID is written).
+pub fn validate_run_id(input: &str) -> Result<(), RiceError> {
+    let run_id = input.trim();
+    if run_id.is_empty() {
+        return Err(invalid("run ID cannot be empty"));
+    }
+    if run_id.len() > 64 {
+        return Err(invalid(format!(
+            "run ID is {} characters long, the limit is 64",
+            run_id.len()
+        )));
+    }
+    match run_id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        Some(c) => Err(invalid(format!(
+            "run ID may only contain letters, digits, '.', '_' and '-', not {:?}",
+            c
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_instance_url(input: &str) -> Result<(), RiceError> {
+    match unix_socket_path(input) {
+        Some(path) if path.as_os_str().is_empty() => {
+            Err(invalid("missing socket path after 'unix:'"))
+        }
+        Some(_) => Ok(()),
+        None => parse_host_port(input).map(|_| ()),
+    }
+}
+
+/// Like [`validate_instance_url`], but State is reached over TCP, so a
+/// Unix socket is refused.
+pub fn validate_state_url(input: &str) -> Result<(), RiceError> {
+    if unix_socket_path(input).is_some() {
+        return Err(invalid(
+            "Unix sockets are only supported for Storage; use a host:port address",
+        ));
+    }
+    validate_instance_url(input)
+}
+
+/// The socket path of a `unix:/path/to/rice.sock` instance URL.
+pub fn unix_socket_path(instance_url: &str) -> Option<&Path> {
+    instance_url.trim().strip_prefix("unix:").map(Path::new)
+}
+
+pub fn validate_http_port(input: &str) -> Result<(), RiceError> {
+    match input.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(()),
+        _ => Err(invalid(format!(
+            "'{}' is not a port number (1-65535)",
+            input
+        ))),
+    }
+}
+
+pub fn validate_optional_http_port(input: &str) -> Result<(), RiceError> {
+    if input.is_empty() {
+        Ok(())
+    } else {
+        validate_http_port(input)
+    }
+}
+
+/// Checks a value for a Rice variable written without going through the
+/// setup prompts (`config --set`, `import`).
+pub fn validate_env_value(var: &str, value: &str) -> Result<(), RiceError> {
+    match var {
+        "STORAGE_INSTANCE_URL" => validate_instance_url(value),
+        "STATE_INSTANCE_URL" => validate_state_url(value),
+        "STORAGE_HTTP_PORT" => validate_http_port(value),
+        "STATE_HTTP_PORT" => validate_optional_http_port(value),
+        "STATE_RUN_ID" => validate_run_id(value),
+        "STORAGE_ENABLED" | "STATE_ENABLED" => parse_env_bool(value)
+            .map(|_| ())
+            .ok_or_else(|| invalid(format!("'{}' is not true or false", value))),
+        _ => Ok(()),
+    }
+}
+
+/// Splits an instance URL into its host and optional port. Accepts `host`,
+/// `host:port`, IPv6 literals like `[::1]:50051` (or bare `::1` without a
+/// port), and `http(s)://` URLs (any path is ignored). IPv6 hosts are
+/// returned without brackets.
+pub fn parse_host_port(input: &str) -> Result<(String, Option<u16>), RiceError> {
+    let mut rest = input.trim();
+    if let Some((scheme, after)) = rest.split_once("://") {
+        if url_scheme(scheme).is_none() {
+            return Err(invalid(format!(
+                "unsupported scheme '{}', use http or https",
+                scheme
+            )));
+        }
+        rest = after;
+    }
+    let authority = rest.split('/').next().unwrap_or(rest);
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| invalid("missing ']' after IPv6 address"))?;
+        let port = match after {
+            "" => None,
+            _ => Some(
+                after
+                    .strip_prefix(':')
+                    .ok_or_else(|| invalid("expected ':' after ']'"))?,
+            ),
+        };
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(invalid(format!("'{}' is not a valid IPv6 address", host)));
+        }
+        (host, port)
+    } else if authority.matches(':').count() > 1 {
+        // Without brackets, a trailing `:port` can't be told apart from the
+        // address, so only a whole address is accepted.
+        if rest != input.trim() {
+            return Err(invalid(
+                "IPv6 addresses in URLs must be bracketed, e.g. http://[::1]:3000",
+            ));
+        }
+        if authority.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(invalid(
+                "IPv6 addresses with a port must be bracketed, e.g. [::1]:50051",
+            ));
+        }
+        (authority, None)
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    if !authority.starts_with('[')
+        && host.parse::<std::net::Ipv6Addr>().is_err()
+        && !host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+    {
+        return Err(invalid(format!("'{}' is not a valid host", host)));
+    }
+
+    let port = match port {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(port),
+            _ => return Err(invalid(format!("'{}' is not a valid port", port))),
+        },
+        None => None,
+    };
+
+    Ok((host.to_string(), port))
+}
+
+/// Normalizes a URL scheme, which is case-insensitive, to http or https.
+fn url_scheme(scheme: &str) -> Option<Scheme> {
+    if scheme.eq_ignore_ascii_case("http") {
+        Some(Scheme::Http)
+    } else if scheme.eq_ignore_ascii_case("https") {
+        Some(Scheme::Https)
+    } else {
+        None
+    }
+}
+
+/// The scheme of a full `http(s)://` instance URL, if it has one.
+fn instance_scheme(instance_url: &str) -> Option<Scheme> {
+    let (scheme, _) = instance_url.trim().split_once("://")?;
+    url_scheme(scheme)
+}
+
+/// Formats a host for use in a URL or socket address, bracketing IPv6 literals.
+fn url_host(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+/// Whether this process runs in a container: Docker's `/.dockerenv`,
+/// Podman's `/run/.containerenv`, or a container runtime in
+/// `/proc/1/cgroup`.
+pub fn in_container() -> bool {
+    if Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists() {
+        return true;
+    }
+    fs::read_to_string("/proc/1/cgroup").is_ok_and(|cgroup| {
+        ["docker", "kubepods", "containerd", "libpod"]
+            .iter()
+            .any(|runtime| cgroup.contains(runtime))
+    })
+}
+
+/// `instance_url` with its loopback host replaced by
+/// `host.docker.internal`, through which a container reaches its host.
+/// `None` when the host is not loopback.
+pub fn docker_host_url(instance_url: &str) -> Option<String> {
+    let endpoint = Endpoint::new(instance_url, "").ok()?;
+    if endpoint.socket.is_some() {
+        return None;
+    }
+    let host = endpoint.host;
+    if !matches!(host.as_str(), "localhost" | "127.0.0.1" | "::1") {
+        return None;
+    }
+    let bracketed = url_host(&host);
+    let needle = if instance_url.contains(&bracketed) {
+        bracketed
+    } else {
+        host
+    };
+    Some(instance_url.replacen(&needle, "host.docker.internal", 1))
+}
+
+/// A Rice instance resolved from its instance URL and separate HTTP port.
+/// A bare `host:port` instance URL names the gRPC port, and HTTP goes to
+/// the HTTP port on the same host; a full `http(s)://` URL keeps its own
+/// scheme and port for both. A `unix:/path` instance URL serves HTTP over
+/// that socket, with no ports at all.
+pub struct Endpoint {
+    pub host: String,
+    pub scheme: Option<Scheme>,
+    pub grpc_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub socket: Option<PathBuf>,
+}
+
+impl Endpoint {
+    /// `http_port` may be empty when only the gRPC address is needed.
+    pub fn new(instance_url: &str, http_port: &str) -> Result<Self, RiceError> {
+        if let Some(path) = unix_socket_path(instance_url) {
+            validate_instance_url(instance_url)?;
+            return Ok(Endpoint {
+                host: "localhost".into(),
+                scheme: None,
+                grpc_port: None,
+                http_port: None,
+                socket: Some(path.to_path_buf()),
+            });
+        }
+        let (host, port) = parse_host_port(instance_url)?;
+        let scheme = instance_scheme(instance_url);
+        let http_port = if scheme.is_some() {
+            port
+        } else if http_port.is_empty() {
+            None
+        } else {
+            validate_http_port(http_port)?;
+            http_port.parse().ok()
+        };
+        Ok(Endpoint {
+            host,
+            scheme,
+            grpc_port: port,
+            http_port,
+            socket: None,
+        })
+    }
+
+    /// The gRPC `host:port`, defaulting the port to 50051.
+    pub fn grpc_addr(&self) -> String {
+        format!(
+            "{}:{}",
+            url_host(&self.host),
+            self.grpc_port.unwrap_or(50051)
+        )
+    }
+
+    /// The URL of `path` on the HTTP port. `scheme` overrides the one in
+    /// the instance URL, which otherwise defaults to http.
+    pub fn http_health_url(&self, scheme: Option<Scheme>, path: &str) -> String {
+        let scheme = scheme.or(self.scheme).unwrap_or(Scheme::Http).as_str();
+        let host = url_host(&self.host);
+        match self.http_port {
+            Some(port) => format!("{}://{}:{}{}", scheme, host, port, path),
+            None => format!("{}://{}{}", scheme, host, path),
+        }
+    }
+
+    /// The URL of the standard gRPC health-checking method on the gRPC
+    /// port, with the same scheme rules as [`Endpoint::http_health_url`].
+    pub fn grpc_health_url(&self, scheme: Option<Scheme>) -> String {
+        let scheme = scheme.or(self.scheme).unwrap_or(Scheme::Http).as_str();
+        format!(
+            "{}://{}/grpc.health.v1.Health/Check",
+            scheme,
+            self.grpc_addr()
+        )
+    }
+}
+
+/// The HTTP port an `http(s)://` instance URL fixes: its own port, else the
+/// scheme's default. `None` for a bare `host[:port]`, whose HTTP port is
+/// configured separately.
+pub fn url_http_port(instance_url: &str) -> Option<u16> {
+    let endpoint = Endpoint::new(instance_url, "").ok()?;
+    let default_port = match endpoint.scheme? {
+        Scheme::Http => 80,
+        Scheme::Https => 443,
+    };
+    Some(endpoint.http_port.unwrap_or(default_port))
+}
+
+/// Where State is verified: its HTTP health endpoint when `STATE_HTTP_PORT`
+/// is set, else its gRPC port.
+pub enum StateTarget {
+    Grpc(String),
+    Http(String),
+}
+
+impl StateTarget {
+    /// The health URL takes `scheme` and `health_path` when it is HTTP.
+    pub fn new(
+        state_url: &str,
+        http_port: &str,
+        scheme: Option<Scheme>,
+        health_path: &str,
+    ) -> Result<Self, RiceError> {
+        validate_optional_http_port(http_port)
+            .map_err(|e| invalid(format!("Invalid STATE_HTTP_PORT: {}", e)))?;
+        validate_state_url(state_url)
+            .map_err(|e| invalid(format!("Invalid STATE_INSTANCE_URL: {}", e)))?;
+        let endpoint = Endpoint::new(state_url, http_port)
+            .map_err(|e| invalid(format!("Invalid STATE_INSTANCE_URL: {}", e)))?;
+        if http_port.is_empty() {
+            Ok(StateTarget::Grpc(endpoint.grpc_addr()))
+        } else {
+            Ok(StateTarget::Http(
+                endpoint.http_health_url(scheme, health_path),
+            ))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            StateTarget::Grpc(addr) => addr,
+            StateTarget::Http(url) => url,
+        }
+    }
+}
+
+/// An `http(s)://` instance URL fixes the HTTP port itself, so a different
+/// separate port would be silently ignored. Returns the URL's port instead,
+/// with a warning saying so; other ports pass through.
+pub fn reconcile_http_port(instance_url: &str, http_port: &str) -> (String, Option<String>) {
+    match url_http_port(instance_url) {
+        Some(port) if !http_port.trim().is_empty() && http_port.trim() != port.to_string() => (
+            port.to_string(),
+            Some(format!(
+                "Instance URL {} already sets HTTP port {}, so port {} is ignored",
+                instance_url,
+                port,
+                http_port.trim()
+            )),
+        ),
+        _ => (http_port.to_string(), None),
+    }
+}
+
+/// Resolves `host` to its addresses, as a DNS preflight before connecting.
+pub async fn resolve_host(
+    host: &str,
+    timeout: std::time::Duration,
+) -> Result<Vec<std::net::IpAddr>, RiceError> {
+    log::debug!("DNS lookup {}", host);
+    match tokio::time::timeout(timeout, tokio::net::lookup_host((host, 0))).await {
+        Ok(Ok(addrs)) => {
+            let mut ips: Vec<std::net::IpAddr> = Vec::new();
+            for addr in addrs {
+                if !ips.contains(&addr.ip()) {
+                    ips.push(addr.ip());
+                }
+            }
+            Ok(ips)
+        }
+        Ok(Err(e)) => Err(RiceError::Unreachable(e.to_string())),
+        Err(_) => Err(RiceError::Unreachable("timed out".into())),
+    }
+}
+
+/// State's gRPC port has no HTTP health endpoint, so a TCP connect is the
+/// reachability signal.
+pub async fn probe_state(addr: &str, timeout: std::time::Duration) -> Result<(), RiceError> {
+    log::debug!("TCP connect {}", addr);
+    let started = Instant::now();
+    let result = match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(RiceError::Unreachable(e.to_string())),
+        Err(_) => Err(RiceError::Unreachable("timed out".into())),
+    };
+    log::debug!(
+        "TCP connect {} -> {} ({} ms)",
+        addr,
+        if result.is_ok() {
+            "connected"
+        } else {
+            "failed"
+        },
+        started.elapsed().as_millis()
+    );
+    result
+}
+
+/// Serving status reported by a gRPC health check.
+#[derive(Clone, Copy, PartialEq)]
+pub enum GrpcStatus {
+    Serving,
+    NotServing,
+    Unknown,
+    ServiceUnknown,
+    /// The server speaks gRPC but does not implement the health service.
+    Unimplemented,
+}
+
+impl GrpcStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GrpcStatus::Serving => "SERVING",
+            GrpcStatus::NotServing => "NOT_SERVING",
+            GrpcStatus::Unknown => "UNKNOWN",
+            GrpcStatus::ServiceUnknown => "SERVICE_UNKNOWN",
+            GrpcStatus::Unimplemented => "UNIMPLEMENTED",
+        }
+    }
+
+    /// The port is usable if the server is serving, or at least answers
+    /// gRPC without offering the health service.
+    pub fn is_reachable(self) -> bool {
+        matches!(self, GrpcStatus::Serving | GrpcStatus::Unimplemented)
+    }
+
+    /// Decodes a length-prefixed `HealthCheckResponse` frame. The message
+    /// has a single enum field, so an empty message means UNKNOWN.
+    pub fn from_frame(frame: &[u8]) -> Result<Self, RiceError> {
+        let parse_error = |message: &str| RiceError::Parse(message.to_string());
+        let message = match frame {
+            [0, len @ ..] if len.len() >= 4 => {
+                let size = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                len.get(4..4 + size)
+                    .ok_or_else(|| parse_error("truncated gRPC response"))?
+            }
+            [1, ..] => return Err(parse_error("compressed gRPC response")),
+            _ => return Err(parse_error("empty gRPC response")),
+        };
+        match message {
+            [] => Ok(GrpcStatus::Unknown),
+            [0x08, 0, ..] => Ok(GrpcStatus::Unknown),
+            [0x08, 1, ..] => Ok(GrpcStatus::Serving),
+            [0x08, 2, ..] => Ok(GrpcStatus::NotServing),
+            [0x08, 3, ..] => Ok(GrpcStatus::ServiceUnknown),
+            _ => Err(parse_error("unexpected gRPC health response")),
+        }
+    }
+}
+
+/// Calls `grpc.health.v1.Health/Check` for the whole server (empty service
+/// name), authenticating with a bearer token when one is set.
+pub async fn probe_grpc(client: &Client, url: &str, token: &str) -> Result<GrpcStatus, RiceError> {
+    let mut request = client
+        .post(url)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(vec![0u8, 0, 0, 0, 0]);
+    if !token.is_empty() {
+        request = request.bearer_auth(token);
+    }
+
+    log::debug!("POST {}", url);
+    let started = Instant::now();
+    let res = request.send().await?;
+    log::debug!(
+        "POST {} -> {} ({} ms)",
+        url,
+        res.status(),
+        started.elapsed().as_millis()
+    );
+    if !res.status().is_success() {
+        return Err(RiceError::Unhealthy(format!(
+            "HTTP status {}",
+            res.status()
+        )));
+    }
+
+    // Errors come back as a trailers-only response, so grpc-status is a header.
+    if let Some(code) = res.headers().get("grpc-status") {
+        let code = code.to_str().unwrap_or_default();
+        match code {
+            "0" => {}
+            "12" => return Ok(GrpcStatus::Unimplemented),
+            _ => {
+                let message = res
+                    .headers()
+                    .get("grpc-message")
+                    .and_then(|m| m.to_str().ok())
+                    .unwrap_or_default();
+                return Err(RiceError::Unhealthy(
+                    format!("grpc-status {} {}", code, message)
+                        .trim_end()
+                        .into(),
+                ));
+            }
+        }
+    }
+
+    let body = res.bytes().await?;
+    GrpcStatus::from_frame(&body)
+}
+
+/// GETs the health URL, authenticating with a bearer token when one is set.
+pub async fn health_request(
+    client: &Client,
+    url: &str,
+    token: &str,
+) -> Result<Response, RiceError> {
+    let mut request = client.get(url);
+    if !token.is_empty() {
+        request = request.bearer_auth(token);
+    }
+
+    log::debug!("GET {}", url);
+    let started = Instant::now();
+    let result = request.send().await;
+    let elapsed = started.elapsed().as_millis();
+    match &result {
+        Ok(res) => log::debug!("GET {} -> {} ({} ms)", url, res.status(), elapsed),
+        Err(e) => log::debug!("GET {} failed after {} ms: {}", url, elapsed, e),
+    }
+    Ok(result?)
+}
+
+/// Masks a secret while hinting at its length, so an unset token is obvious.
+pub fn mask_secret(value: &str) -> String {
+    format!("******** (len {})", value.chars().count())
+}
+
+/// Masks the value of a token line such as `STORAGE_AUTH_TOKEN=...` or
+/// `auth_token = "..."`; other lines pass through.
+pub fn mask_env_line(line: &str) -> std::borrow::Cow<'_, str> {
+    match line.split_once('=') {
+        Some((key, value))
+            if (SECRET_ENV_KEYS.contains(&key.trim()) || key.trim() == "auth_token")
+                && !value.trim().is_empty() =>
+        {
+            format!("{}=********", key).into()
+        }
+        _ => line.into(),
+    }
+}
+
+/// Copies `path` to `<path>.bak.<unix_ts>` and returns the backup path.
+pub fn backup_file(path: &Path) -> Result<PathBuf, RiceError> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let backup = PathBuf::from(format!("{}.bak.{}", path.display(), timestamp));
+    fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// Where the effective value of a Rice variable comes from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueSource {
+    Keyring,
+    Environment,
+    EnvFile,
+    LocalEnvFile,
+    ConfigFile,
+    Default,
+}
+
+impl ValueSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ValueSource::Keyring => "keyring",
+            ValueSource::Environment => "environment",
+            ValueSource::EnvFile => "env_file",
+            ValueSource::LocalEnvFile => "env_local_file",
+            ValueSource::ConfigFile => "config_file",
+            ValueSource::Default => "default",
+        }
+    }
+}
+
+/// Reads a boolean env value such as `STORAGE_ENABLED`.
+pub fn parse_env_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whole milliseconds of `latency`, as reported by `check`.
+fn latency_ms(latency: std::time::Duration) -> u64 {
+    latency.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// What counts as a healthy answer from an HTTP health endpoint.
+#[derive(Clone, Default)]
+pub struct HealthCriteria {
+    /// Status to require instead of any 2xx
+    pub expect_status: Option<u16>,
+    /// Further statuses that also count as healthy, such as 401 from a
+    /// gateway that only answers authenticated requests
+    pub assume_healthy_on: Vec<u16>,
+    /// Text the response body must contain
+    pub body_contains: Option<String>,
+}
+
+/// Outcome of one health probe. It displays as the line `rice-cli check`
+/// prints, e.g. `Storage is healthy (Status: 200 OK, 12ms)`.
+#[derive(Clone, Serialize)]
+pub struct HealthReport {
+    /// `storage` or `state`
+    pub service: &'static str,
+    /// Health URL, or the `host:port` of a TCP probe
+    pub url: String,
+    /// `None` when no HTTP response was received, and for TCP probes
+    pub status_code: Option<u16>,
+    pub healthy: bool,
+    /// Time until the response headers (or TCP connect), `None` when the
+    /// service did not answer
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl HealthReport {
+    /// A probe that got no answer.
+    pub fn unreachable(service: &'static str, url: &str, error: impl Into<String>) -> Self {
+        HealthReport {
+            service,
+            url: url.to_string(),
+            status_code: None,
+            healthy: false,
+            latency_ms: None,
+            error: Some(error.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.service {
+            "storage" => "Storage",
+            "state" => "State",
+            other => other,
+        };
+        let latency = self.latency_ms.unwrap_or_default();
+        match self.status_code {
+            Some(code) => {
+                let status = StatusCode::from_u16(code)
+                    .map(|status| status.to_string())
+                    .unwrap_or_else(|_| code.to_string());
+                write!(
+                    f,
+                    "{} is {} (Status: {}, {}ms",
+                    name,
+                    if self.healthy { "healthy" } else { "unhealthy" },
+                    status,
+                    latency
+                )?;
+                if let Some(error) = &self.error {
+                    write!(f, ", {}", error)?;
+                }
+                write!(f, ")")
+            }
+            None if self.healthy => {
+                write!(f, "{} is reachable at {} ({}ms)", name, self.url, latency)
+            }
+            None => write!(
+                f,
+                "Failed to reach {} at {}: {}",
+                name,
+                self.url,
+                self.error.as_deref().unwrap_or("no answer")
+            ),
+        }
+    }
+}
+
+/// GETs the health URL once and judges the answer by `criteria`. `Err`
+/// means no HTTP response was received; describe it and pass it to
+/// [`HealthReport::unreachable`] to get a report.
+pub async fn check_health(
+    client: &Client,
+    service: &'static str,
+    url: &str,
+    token: &str,
+    criteria: &HealthCriteria,
+) -> Result<HealthReport, RiceError> {
+    let started = Instant::now();
+    let res = health_request(client, url, token).await?;
+    let latency = started.elapsed();
+    let status = res.status();
+    let status_ok = match criteria.expect_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    } || criteria.assume_healthy_on.contains(&status.as_u16());
+    let error = match &criteria.body_contains {
+        Some(text) if !res.text().await?.contains(text.as_str()) => {
+            Some(format!("body does not contain {:?}", text))
+        }
+        _ => None,
+    };
+    Ok(HealthReport {
+        service,
+        url: url.to_string(),
+        status_code: Some(status.as_u16()),
+        healthy: status_ok && error.is_none(),
+        latency_ms: Some(latency_ms(latency)),
+        error,
+    })
+}
+
+/// Checks that `addr` accepts TCP connections, for a gRPC port without an
+/// HTTP health endpoint.
+pub async fn check_reachable(
+    service: &'static str,
+    addr: &str,
+    timeout: std::time::Duration,
+) -> HealthReport {
+    let started = Instant::now();
+    match probe_state(addr, timeout).await {
+        Ok(()) => HealthReport {
+            service,
+            url: addr.to_string(),
+            status_code: None,
+            healthy: true,
+            latency_ms: Some(latency_ms(started.elapsed())),
+            error: None,
+        },
+        Err(e) => HealthReport::unreachable(service, addr, e.to_string()),
+    }
+}
+
+/// How a probe is repeated until it succeeds.
+#[derive(Clone, Copy)]
+pub enum Polling {
+    /// Retry this many extra times, backing off between attempts.
+    Retries(u32, Backoff),
+    /// Poll every second until the limit elapses.
+    Wait(std::time::Duration),
+}
+
+impl Polling {
+    /// A `wait` limit takes precedence over `retries`.
+    pub fn new(retries: u32, wait: Option<u64>, backoff: Backoff) -> Polling {
+        match wait {
+            Some(secs) => Polling::Wait(std::time::Duration::from_secs(secs)),
+            None => Polling::Retries(retries, backoff),
+        }
+    }
+}
+
+/// Exponential backoff between retried attempts.
+#[derive(Clone, Copy)]
+pub struct Backoff {
+    pub base: std::time::Duration,
+    pub max: std::time::Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            base: std::time::Duration::from_millis(500),
+            max: std::time::Duration::from_secs(5),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay after failed attempt `attempt` (from 1): the base doubled
+    /// per attempt up to the cap, then jittered into its upper half so that
+    /// clients retrying together spread out instead of arriving at once.
+    pub fn delay(self, attempt: u32) -> std::time::Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max);
+        delay / 2 + (delay / 2).mul_f64(jitter())
+    }
+}
+
+/// A random fraction in `[0, 1)`. `RandomState` is seeded per instance,
+/// which is plenty for spreading out retries.
+fn jitter() -> f64 {
+    use std::hash::BuildHasher;
+    let bits = std::hash::RandomState::new().hash_one(());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Formats `time` as an ISO-8601 UTC timestamp such as
+/// `2024-05-01T12:34:56Z`.
+pub fn iso8601_utc(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+/// Whether `.gitignore` content ignores `name`. Only the basic syntax is
+/// understood: `*` wildcards, a leading `/`, `!` negation, last match wins.
+pub fn gitignore_ignores(gitignore: &str, name: &str) -> bool {
+    let mut ignored = false;
+    for line in gitignore.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line),
+        };
+        if glob_matches(pattern.trim_start_matches('/'), name) {
+            ignored = !negated;
+        }
+    }
+    ignored
+}
+
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| glob_matches(rest, &name[i..]))
+        }
+    }
+}
+
+/// Returns true for the lines setup writes to .env: the header comments and
+/// the Rice keys, named with `prefix`.
+pub fn is_rice_env_line(line: &str, prefix: &str) -> bool {
+    let line = line.trim();
+    if line == RICE_ENV_HEADER || line.starts_with(ENV_PREFIX_MARKER) {
+        return true;
+    }
+    env_line_key(line).is_some_and(|key| {
+        key.strip_prefix(prefix)
+            .is_some_and(|key| RICE_ENV_KEYS.contains(&key))
+    })
+}
+
+/// The `--env-prefix` recorded in .env content, or "" when there is none.
+pub fn env_prefix(content: &str) -> &str {
+    content
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(ENV_PREFIX_MARKER))
+        .map_or("", str::trim)
+}
+
+/// Env prefixes are empty or a variable-name start: letters, digits and
+/// `_`, not beginning with a digit.
+pub fn validate_env_prefix(input: &str) -> Result<(), RiceError> {
+    if input.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(invalid("env prefix cannot start with a digit"));
+    }
+    match input
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_'))
+    {
+        Some(c) => Err(invalid(format!(
+            "env prefix may only contain letters, digits and '_', not {:?}",
+            c
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Tokens left as they came from a template or example, compared ignoring
+/// case.
+const PLACEHOLDER_TOKENS: [&str; 7] = [
+    "changeme",
+    "change-me",
+    "change_me",
+    "token",
+    "secret",
+    "todo",
+    "replace-me",
+];
+
+/// Whether `token` looks like a placeholder rather than a real token: a
+/// known placeholder, `your-...`, `<...>`, or only `x`s.
+pub fn is_placeholder_token(token: &str) -> bool {
+    let token = token.trim().to_ascii_lowercase();
+    PLACEHOLDER_TOKENS.contains(&token.as_str())
+        || token.starts_with("your-")
+        || token.starts_with("your_")
+        || (token.starts_with('<') && token.ends_with('>'))
+        || (!token.is_empty() && token.chars().all(|c| c == 'x'))
+}
+
+/// Double-quotes a .env value when dotenv parsers would otherwise misread it,
+/// escaping backslashes, quotes, `$` and newlines. Plain values stay unquoted.
+pub fn quote_env_value(value: &str) -> std::borrow::Cow<'_, str> {
+    let needs_quotes = value
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '#' | '=' | '"' | '\'' | '\\' | '$' | '`'));
+    if !needs_quotes {
+        return value.into();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted.into()
+}
+
+/// Single-quotes a value for POSIX shells unless it only has characters no
+/// shell treats specially. Embedded single quotes become `'\''`.
+pub fn shell_quote(value: &str) -> std::borrow::Cow<'_, str> {
+    let plain = !value.is_empty()
+        && value.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(c, '_' | '-' | '.' | '/' | ':' | '@' | '%' | '+' | ',' | '=')
+        });
+    if plain {
+        return value.into();
+    }
+    format!("'{}'", value.replace('\'', "'\\''")).into()
+}
+
+/// The variable a value refers to when it is exactly `${NAME}`, such as
+/// `STORAGE_AUTH_TOKEN=${CI_RICE_TOKEN}`.
+pub fn env_reference(value: &str) -> Option<&str> {
+    let name = value.trim().strip_prefix("${")?.strip_suffix('}')?;
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some(name)
+}
+
+/// The value of the first `key=` line in .env content as written, without
+/// surrounding quotes and before any `${...}` substitution.
+pub fn env_file_value(content: &str, key: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        if env_line_key(line)? != key {
+            return None;
+        }
+        let (_, value) = line.split_once('=')?;
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote));
+        Some(unquoted.unwrap_or(value).to_string())
+    })
+}
+
+/// Rice keys that .env content sets outside the Rice block, i.e. before its
+/// header or anywhere when there is no header, with their values. Keys are
+/// matched with `prefix` and returned without it.
+pub fn foreign_env_values(existing: &str, prefix: &str) -> Vec<(String, String)> {
+    let outside: Vec<&str> = existing
+        .lines()
+        .take_while(|line| line.trim() != RICE_ENV_HEADER)
+        .collect();
+    dotenvy::from_read_iter(outside.join("\n").as_bytes())
+        .filter_map(Result::ok)
+        .filter_map(|(key, value)| {
+            let key = key.strip_prefix(prefix)?;
+            RICE_ENV_KEYS
+                .contains(&key)
+                .then(|| (key.to_string(), value))
+        })
+        .collect()
+}
+
+/// The key a `.env` line assigns, accepting the shell-style `export KEY=`
+/// form that dotenv loaders also read.
+fn env_line_key(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    line.split_once('=').map(|(key, _)| key.trim())
+}
+
+/// Whether .env content assigns `key`.
+fn env_has_key(content: &str, key: &str) -> bool {
+    content.lines().any(|line| env_line_key(line) == Some(key))
+}
+
+/// Adds the Rice keys missing from `.env.example` content, each with a
+/// comment and placeholder value. Keys already there are left as they are.
+/// Keys are named with `prefix`. Returns `None` when nothing is missing.
+pub fn merge_env_example(existing: &str, prefix: &str) -> Option<String> {
+    let missing: Vec<_> = ENV_EXAMPLE_ENTRIES
+        .iter()
+        .filter(|(key, _, _)| !env_has_key(existing, &format!("{}{}", prefix, key)))
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+
+    let mut content = existing.trim_end().to_string();
+    if !content.is_empty() {
+        content.push_str("\n\n");
+    }
+    if !existing.lines().any(|line| line.trim() == RICE_ENV_HEADER) {
+        content.push_str(RICE_ENV_HEADER);
+        content.push('\n');
+    }
+    for (key, placeholder, comment) in missing {
+        content.push_str(&format!(
+            "# {}\n{}{}={}\n",
+            comment, prefix, key, placeholder
+        ));
+    }
+    Some(content)
+}
+
+/// Removes the lines setup writes to .env content. Returns what is left
+/// and the number of lines removed.
+pub fn strip_rice_env(content: &str, prefix: &str) -> (String, usize) {
+    strip_env_lines(content, |line| is_rice_env_line(line, prefix))
+}
+
+/// Removes the Rice keys from `.env.example` content, along with the
+/// comments [`merge_env_example`] writes above them.
+pub fn strip_rice_env_example(content: &str, prefix: &str) -> (String, usize) {
+    strip_env_lines(content, |line| {
+        is_rice_env_line(line, prefix)
+            || line.trim().strip_prefix("# ").is_some_and(|comment| {
+                ENV_EXAMPLE_ENTRIES
+                    .iter()
+                    .any(|&(_, _, entry)| entry == comment)
+            })
+    })
+}
+
+fn strip_env_lines(content: &str, is_rice: impl Fn(&str) -> bool) -> (String, usize) {
+    let kept: Vec<&str> = content.lines().filter(|line| !is_rice(line)).collect();
+    let removed = content.lines().count() - kept.len();
+    let remaining = kept.join("\n").trim_end().to_string();
+    if remaining.is_empty() {
+        (remaining, removed)
+    } else {
+        (remaining + "\n", removed)
+    }
+}
+
+/// Merges Rice variables into existing .env content. Keys already present are
+/// updated in place; missing keys are added to the Rice block, which is created
+/// (with its header) only if the file doesn't have one yet. Keys are written
+/// with `prefix`, which is recorded under the header when not empty.
+pub fn merge_env(existing: &str, prefix: &str, values: &[(&str, &str)]) -> String {
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|line| !line.trim().starts_with(ENV_PREFIX_MARKER))
+        .map(String::from)
+        .collect();
+    let mut missing = Vec::new();
+
+    for (key, value) in values {
+        let key = format!("{}{}", prefix, key);
+        let line = format!("{}={}", key, quote_env_value(value));
+        let position = lines
+            .iter()
+            .position(|existing_line| env_line_key(existing_line) == Some(key.as_str()));
+        match position {
+            // An `export KEY=` line stays exported.
+            Some(index) if lines[index].trim_start().starts_with("export ") => {
+                lines[index] = format!("export {}", line)
+            }
+            Some(index) => lines[index] = line,
+            None => missing.push(line),
+        }
+    }
+
+    if !missing.is_empty() {
+        let has_header = lines.iter().any(|line| line.trim() == RICE_ENV_HEADER);
+        match lines
+            .iter()
+            .rposition(|line| is_rice_env_line(line, prefix))
+        {
+            Some(last_rice_line) if has_header => {
+                lines.splice(last_rice_line + 1..last_rice_line + 1, missing);
+            }
+            _ => {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(RICE_ENV_HEADER.to_string());
+                lines.extend(missing);
+            }
+        }
+    }
+    if !prefix.is_empty() {
+        let position = lines
+            .iter()
+            .position(|line| line.trim() == RICE_ENV_HEADER)
+            .map_or(0, |header| header + 1);
+        lines.insert(position, format!("{} {}", ENV_PREFIX_MARKER, prefix));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Blanks out the comments in JS `content`, and the braces and colons inside
+/// its strings, so that a plain scan only sees code. Offsets and line breaks
+/// are kept; string text is kept too, so quoted keys are still found.
+fn js_code(content: &str) -> String {
+    let mut code = String::with_capacity(content.len());
+    let blank = |code: &mut String, c: char| {
+        if c == '\n' {
+            code.push(c);
+        } else {
+            code.extend(std::iter::repeat_n(' ', c.len_utf8()));
+        }
+    };
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                blank(&mut code, c);
+                while let Some(next) = chars.next_if(|&next| next != '\n') {
+                    blank(&mut code, next);
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                blank(&mut code, c);
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    blank(&mut code, next);
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+            }
+            '"' | '\'' | '`' => {
+                code.push(c);
+                while let Some(next) = chars.next() {
+                    match next {
+                        '{' | '}' | ':' => blank(&mut code, next),
+                        _ => code.push(next),
+                    }
+                    if next == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            code.push(escaped);
+                        }
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            _ => code.push(c),
+        }
+    }
+    code
+}
+
+/// Returns the offset just past the `:` of the first `key:` (optionally
+/// quoted) in `content`.
+fn find_config_key(content: &str, key: &str) -> Option<usize> {
+    content.match_indices(key).find_map(|(index, _)| {
+        let before = content[..index].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        let after = &content[index + key.len()..];
+        let trimmed = after.trim_start_matches(['"', '\'']).trim_start();
+        let colon = trimmed.strip_prefix(':')?;
+        Some(content.len() - colon.len())
+    })
+}
+
+/// Locates the `{ ... }` body of `<section>:` in rice.config.js, returning
+/// the body's byte offset within `content` along with its text.
+fn config_section<'a>(content: &'a str, section: &str) -> Option<(usize, &'a str)> {
+    let start = find_config_key(content, section)?;
+    let body = content[start..].trim_start().strip_prefix('{')?;
+    let offset = content.len() - body.len();
+
+    let mut depth = 1;
+    let end = body.char_indices().find_map(|(index, c)| {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        (depth == 0).then_some(index)
+    })?;
+    Some((offset, &body[..end]))
+}
+
+fn parse_config_bool(value: &str) -> Option<bool> {
+    let value = value.trim_start();
+    if value.starts_with("true") {
+        Some(true)
+    } else if value.starts_with("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Returns the offset of the first unmatched brace, if any.
+fn unbalanced_brace(content: &str) -> Option<usize> {
+    let mut open = Vec::new();
+    for (index, c) in content.char_indices() {
+        match c {
+            '{' => open.push(index),
+            '}' if open.pop().is_none() => return Some(index),
+            _ => {}
+        }
+    }
+    open.first().copied()
+}
+
+/// Structurally validates a Rice config file without evaluating it. Returns
+/// the `(storage, state)` enabled flags, or every problem found.
+pub fn validate_config(content: &str, format: ConfigFormat) -> Result<(bool, bool), Vec<String>> {
+    match format {
+        ConfigFormat::Js => validate_js_config(content, true),
+        ConfigFormat::Json => match serde_json::from_str::<serde_json::Value>(content) {
+            Ok(value) if value.is_object() => validate_js_config(content, false),
+            Ok(_) => Err(vec!["the top level must be an object".to_string()]),
+            Err(e) => Err(vec![e.to_string()]),
+        },
+        ConfigFormat::Toml => validate_toml_config(content),
+    }
+}
+
+/// The dotted paths of the keys in a Rice config file, such as
+/// `storage.enabled`, found without evaluating it. Best-effort for
+/// JavaScript: computed keys and spread properties are not seen.
+pub fn config_keys(content: &str, format: ConfigFormat) -> Vec<String> {
+    match format {
+        ConfigFormat::Js | ConfigFormat::Json => js_config_keys(content),
+        ConfigFormat::Toml => {
+            fn walk(table: &toml::Table, prefix: &str, keys: &mut Vec<String>) {
+                for (key, value) in table {
+                    let path = format!("{}{}", prefix, key);
+                    keys.push(path.clone());
+                    if let toml::Value::Table(inner) = value {
+                        walk(inner, &format!("{}.", path), keys);
+                    }
+                }
+            }
+            let mut keys = Vec::new();
+            if let Ok(table) = toml::from_str::<toml::Table>(content) {
+                walk(&table, "", &mut keys);
+            }
+            keys
+        }
+    }
+}
+
+/// Scans object literals for `key:` and quoted `"key":`, skipping strings
+/// and comments, and nests keys by brace depth below the outermost object.
+fn js_config_keys(content: &str) -> Vec<String> {
+    let content = &js_code(content);
+    let mut keys = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut token = String::new();
+    let mut pending = None;
+    let mut in_identifier = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' | '`' => {
+                token.clear();
+                in_identifier = false;
+                while let Some(next) = chars.next() {
+                    match next {
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ if next == c => break,
+                        _ => token.push(next),
+                    }
+                }
+                continue;
+            }
+            ':' if !path.is_empty() && !token.is_empty() => {
+                let mut key: Vec<&str> = path[1..]
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|segment| !segment.is_empty())
+                    .collect();
+                key.push(&token);
+                keys.push(key.join("."));
+                pending = Some(std::mem::take(&mut token));
+            }
+            '{' => path.push(pending.take().unwrap_or_default()),
+            '}' => {
+                path.pop();
+            }
+            ',' => pending = None,
+            _ if c.is_alphanumeric() || c == '_' || c == '$' => {
+                if !in_identifier {
+                    token.clear();
+                }
+                token.push(c);
+                in_identifier = true;
+                continue;
+            }
+            _ if c.is_whitespace() => {
+                in_identifier = false;
+                continue;
+            }
+            _ => {}
+        }
+        token.clear();
+        in_identifier = false;
+    }
+    keys
+}
+
+/// The keys of an `existing` config file that the `generated` one would
+/// drop, leaving out keys nested under one already listed.
+pub fn lost_config_keys(existing: &str, generated: &str, format: ConfigFormat) -> Vec<String> {
+    let kept = config_keys(generated, format);
+    let mut lost: Vec<String> = Vec::new();
+    for key in config_keys(existing, format) {
+        let nested = lost.iter().any(|parent| {
+            key.strip_prefix(parent.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+        });
+        if !kept.contains(&key) && !nested && !lost.contains(&key) {
+            lost.push(key);
+        }
+    }
+    lost
+}
+
+/// Validates JS object syntax with line hints. JSON shares this syntax, so it
+/// is checked here too, just without requiring an export.
+fn validate_js_config(content: &str, require_export: bool) -> Result<(bool, bool), Vec<String>> {
+    let content = &js_code(content);
+    let line_of = |offset: usize| content[..offset].matches('\n').count() + 1;
+    let mut problems = Vec::new();
+
+    if require_export && !content.contains("module.exports") && !content.contains("export default")
+    {
+        problems.push("no `module.exports` or `export default` found".to_string());
+    }
+    if let Some(offset) = unbalanced_brace(content) {
+        problems.push(format!("line {}: unbalanced brace", line_of(offset)));
+    }
+
+    let mut enabled = [false; 2];
+    for (flag, section) in enabled.iter_mut().zip(["storage", "state"]) {
+        let Some(key_offset) = find_config_key(content, section) else {
+            problems.push(format!("missing `{}` section", section));
+            continue;
+        };
+        let Some((body_offset, body)) = config_section(content, section) else {
+            problems.push(format!(
+                "line {}: `{}` must be an object",
+                line_of(key_offset),
+                section
+            ));
+            continue;
+        };
+        let Some(value_offset) = find_config_key(body, "enabled") else {
+            problems.push(format!(
+                "line {}: `{}.enabled` is missing",
+                line_of(key_offset),
+                section
+            ));
+            continue;
+        };
+        match parse_config_bool(&body[value_offset..]) {
+            Some(value) => *flag = value,
+            None => problems.push(format!(
+                "line {}: `{}.enabled` must be true or false",
+                line_of(body_offset + value_offset),
+                section
+            )),
+        }
+    }
+
+    if problems.is_empty() {
+        Ok((enabled[0], enabled[1]))
+    } else {
+        Err(problems)
+    }
+}
+
+/// Accepts the `[rice.storage]`/`[rice.state]` layout written by setup, and
+/// top-level `[storage]`/`[state]` tables from older versions.
+fn validate_toml_config(content: &str) -> Result<(bool, bool), Vec<String>> {
+    let table: toml::Table = toml::from_str(content).map_err(|e| vec![e.to_string()])?;
+    let (table, prefix) = match table.get("rice") {
+        Some(toml::Value::Table(rice)) => (rice, "rice."),
+        Some(_) => return Err(vec!["`rice` must be a table".to_string()]),
+        None => (&table, ""),
+    };
+    let mut problems = Vec::new();
+
+    let mut enabled = [false; 2];
+    for (flag, section) in enabled.iter_mut().zip(["storage", "state"]) {
+        match table.get(section) {
+            None => problems.push(format!("missing `[{}{}]` table", prefix, section)),
+            Some(toml::Value::Table(body)) => match body.get("enabled") {
+                Some(toml::Value::Boolean(value)) => *flag = *value,
+                Some(_) => problems.push(format!(
+                    "`{}{}.enabled` must be true or false",
+                    prefix, section
+                )),
+                None => problems.push(format!("`{}{}.enabled` is missing", prefix, section)),
+            },
+            Some(_) => problems.push(format!("`{}{}` must be a table", prefix, section)),
+        }
+    }
+
+    if problems.is_empty() {
+        Ok((enabled[0], enabled[1]))
+    } else {
+        Err(problems)
+    }
+}
+
+/// Reads the connection values that `setup --config-format toml` writes
+/// under `[rice.storage]`/`[rice.state]`, as Rice variables.
+pub fn toml_env_values(content: &str) -> Vec<(&'static str, String)> {
+    let Ok(table) = toml::from_str::<toml::Table>(content) else {
+        return Vec::new();
+    };
+    let Some(rice) = table.get("rice").and_then(toml::Value::as_table) else {
+        return Vec::new();
+    };
+    [
+        ("STORAGE_ENABLED", "storage", "enabled"),
+        ("STATE_ENABLED", "state", "enabled"),
+    ]
+    .into_iter()
+    .chain(BUNDLE_FIELDS)
+    .filter_map(|(var, section, field)| {
+        let value = match rice.get(section)?.get(field)? {
+            toml::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        Some((var, value))
+    })
+    .collect()
+}
+
+pub const RICERC_FILE: &str = ".ricerc";
+
+/// Finds the `.ricerc` of setup defaults: in the current directory, else in
+/// the home directory.
+pub fn find_ricerc() -> Option<PathBuf> {
+    let home = std::env::home_dir().map(|home| home.join(RICERC_FILE));
+    std::iter::once(PathBuf::from(RICERC_FILE))
+        .chain(home)
+        .find(|path| path.is_file())
+}
+
+/// Reads `.ricerc` content, TOML or (starting with `{`) JSON, with the
+/// export bundle's `storage`/`state` sections and fields, as Rice variables.
+pub fn ricerc_values(content: &str) -> Result<Vec<(&'static str, String)>, RiceError> {
+    let rc: serde_json::Value = if content.trim_start().starts_with('{') {
+        serde_json::from_str(content).map_err(|e| RiceError::Parse(e.to_string()))?
+    } else {
+        toml::from_str(content).map_err(|e| RiceError::Parse(e.to_string()))?
+    };
+    Ok(BUNDLE_FIELDS
+        .into_iter()
+        .filter_map(|(var, section, field)| {
+            let value = match rc.get(section)?.get(field)? {
+                serde_json::Value::String(value) => value.clone(),
+                value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => {
+                    value.to_string()
+                }
+                _ => return None,
+            };
+            Some((var, value))
+        })
+        .collect())
+}
+
+/// Parses a `major.minor.patch` version, tolerating a leading `v` and any
+/// pre-release or build suffix. Missing components count as zero.
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u64>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    Some((major, minor, patch))
+}
+
+/// Extracts the server version from a `/version` response body, which may
+/// be JSON with a `version` field or the bare version string.
+pub fn server_version(body: &str) -> Option<String> {
+    let version = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(json) => json["version"].as_str()?.to_string(),
+        Err(_) => body.trim().to_string(),
+    };
+    parse_version(&version).map(|_| version)
+}
+
+/// Where each Rice variable lives in an export bundle, as (variable,
+/// section, field). The enabled flags are stored as booleans separately.
+pub const BUNDLE_FIELDS: [(&str, &str, &str); 9] = [
+    ("STORAGE_INSTANCE_URL", "storage", "instance_url"),
+    ("STORAGE_USER", "storage", "user"),
+    ("STORAGE_AUTH_TOKEN", "storage", "auth_token"),
+    ("STORAGE_HTTP_PORT", "storage", "http_port"),
+    ("STORAGE_HEALTH_PATH", "storage", "health_path"),
+    ("STATE_INSTANCE_URL", "state", "instance_url"),
+    ("STATE_AUTH_TOKEN", "state", "auth_token"),
+    ("STATE_RUN_ID", "state", "run_id"),
+    ("STATE_HTTP_PORT", "state", "http_port"),
+];
+
+/// Value setup uses for a Rice variable when nothing else provides one.
+pub fn default_env_value(var: &str) -> &'static str {
+    match var {
+        "STORAGE_INSTANCE_URL" | "STATE_INSTANCE_URL" => "localhost:50051",
+        "STORAGE_USER" => "admin",
+        "STORAGE_HTTP_PORT" => "3000",
+        "STORAGE_HEALTH_PATH" => "/health",
+        "STATE_RUN_ID" => "default",
+        _ => "",
+    }
+}
+
+/// Storage settings, as written to the `STORAGE_*` variables.
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub enabled: bool,
+    pub instance_url: String,
+    pub user: String,
+    pub auth_token: String,
+    pub http_port: String,
+    pub health_path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            enabled: true,
+            instance_url: default_env_value("STORAGE_INSTANCE_URL").into(),
+            user: default_env_value("STORAGE_USER").into(),
+            auth_token: String::new(),
+            http_port: default_env_value("STORAGE_HTTP_PORT").into(),
+            health_path: default_env_value("STORAGE_HEALTH_PATH").into(),
+        }
+    }
+}
+
+/// State settings, as written to the `STATE_*` variables. An empty
+/// `http_port` means State is verified on its gRPC port.
+#[derive(Clone)]
+pub struct StateConfig {
+    pub enabled: bool,
+    pub instance_url: String,
+    pub auth_token: String,
+    pub run_id: String,
+    pub http_port: String,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            enabled: true,
+            instance_url: default_env_value("STATE_INSTANCE_URL").into(),
+            auth_token: String::new(),
+            run_id: default_env_value("STATE_RUN_ID").into(),
+            http_port: String::new(),
+        }
+    }
+}
+
+/// The answers `rice-cli setup` collects, with both services enabled on
+/// localhost by default.
+#[derive(Clone, Default)]
+pub struct RiceConfig {
+    pub storage: StorageConfig,
+    pub state: StateConfig,
+}
+
+impl RiceConfig {
+    /// Every Rice variable with its value, in [`RICE_ENV_KEYS`] order.
+    pub fn env_values(&self) -> [(&'static str, &str); 11] {
+        let flag = |enabled: bool| if enabled { "true" } else { "false" };
+        [
+            ("STORAGE_ENABLED", flag(self.storage.enabled)),
+            ("STORAGE_INSTANCE_URL", &self.storage.instance_url),
+            ("STORAGE_USER", &self.storage.user),
+            ("STORAGE_AUTH_TOKEN", &self.storage.auth_token),
+            ("STORAGE_HTTP_PORT", &self.storage.http_port),
+            ("STORAGE_HEALTH_PATH", &self.storage.health_path),
+            ("STATE_ENABLED", flag(self.state.enabled)),
+            ("STATE_INSTANCE_URL", &self.state.instance_url),
+            ("STATE_AUTH_TOKEN", &self.state.auth_token),
+            ("STATE_RUN_ID", &self.state.run_id),
+            ("STATE_HTTP_PORT", &self.state.http_port),
+        ]
+    }
+}
+
+/// Writes a [`RiceConfig`] to the config file and `.env`, as `rice-cli
+/// setup` does once its prompts are answered. [`RiceSetup::new`] uses the
+/// same defaults as the CLI; adjust the fields before calling
+/// [`RiceSetup::apply`].
+pub struct RiceSetup {
+    pub config: RiceConfig,
+    pub config_path: PathBuf,
+    pub config_format: ConfigFormat,
+    /// Detected from the package.json nearest to `config_path` when unset.
+    pub module_format: Option<ModuleFormat>,
+    pub env_path: PathBuf,
+    pub env_mode: EnvMode,
+    /// Prepended to the variable names written to `.env`.
+    pub env_prefix: String,
+    /// Write the auth tokens, rather than empty values, to the files.
+    pub persist_tokens: bool,
+    /// Add missing Rice keys to the `.env.example` next to `env_path`.
+    pub update_example: bool,
+    /// Write `config_path` as a config directory of fragments (see
+    /// [`render_config_fragments`]) rather than a single file.
+    pub fragments: bool,
+}
+
+impl RiceSetup {
+    pub fn new(config: RiceConfig) -> Self {
+        RiceSetup {
+            config,
+            config_path: PathBuf::from(ConfigFormat::Js.file_name()),
+            config_format: ConfigFormat::Js,
+            module_format: None,
+            env_path: PathBuf::from(".env"),
+            env_mode: EnvMode::Merge,
+            env_prefix: String::new(),
+            persist_tokens: true,
+            update_example: true,
+            fragments: false,
+        }
+    }
+
+    /// The variables to write, with empty tokens unless `persist_tokens`.
+    pub fn env_values(&self) -> [(&'static str, &str); 11] {
+        let mut values = self.config.env_values();
+        if !self.persist_tokens {
+            for (var, value) in &mut values {
+                if SECRET_ENV_KEYS.contains(var) {
+                    *value = "";
+                }
+            }
+        }
+        values
+    }
+
+    /// The variables written to `.env`, per [`ConfigFormat::env_file_values`].
+    pub fn env_file_values(&self) -> Vec<(&'static str, &str)> {
+        self.config_format.env_file_values(&self.env_values())
+    }
+
+    /// Whether `.env` is written: always, except next to a TOML config file
+    /// when there are no tokens to keep out of it.
+    pub fn writes_env(&self) -> bool {
+        !self.env_file_values().is_empty()
+    }
+
+    /// The config files to write with their content: the config file, or
+    /// each fragment of a config directory.
+    pub fn config_files(&self) -> Vec<(PathBuf, String)> {
+        if !self.fragments {
+            return vec![(self.config_path.clone(), self.config_content())];
+        }
+        let config = &self.config;
+        render_config_fragments(
+            self.module_format(),
+            config.storage.enabled,
+            config.state.enabled,
+        )
+        .into_iter()
+        .map(|(file, content)| (self.config_path.join(file), content))
+        .collect()
+    }
+
+    fn module_format(&self) -> ModuleFormat {
+        self.module_format
+            .unwrap_or_else(|| ModuleFormat::detect(&self.config_path))
+    }
+
+    pub fn config_content(&self) -> String {
+        let module_format = self.module_format();
+        self.config_format.render(
+            module_format,
+            self.config.storage.enabled,
+            self.config.state.enabled,
+            &self.env_values(),
+        )
+    }
+
+    /// The new `.env` content, written over `existing` per `env_mode`.
+    pub fn env_content(&self, existing: &str) -> String {
+        self.env_mode
+            .apply(existing, &self.env_prefix, &self.env_file_values())
+    }
+
+    /// Whether to add missing keys to `.env.example`; not next to a TOML
+    /// config file, which is where the variables are documented then.
+    pub fn writes_example(&self) -> bool {
+        self.update_example && self.config_format != ConfigFormat::Toml
+    }
+
+    pub fn example_path(&self) -> PathBuf {
+        self.env_path.with_file_name(".env.example")
+    }
+
+    /// Writes the config file (or fragments), then `.env` and `.env.example` when
+    /// [`RiceSetup::writes_env`]. An existing config file is overwritten.
+    /// Returns the paths written.
+    pub fn apply(&self) -> Result<Vec<PathBuf>, RiceError> {
+        let read = |path: &Path| match fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            result => result,
+        };
+        if self.fragments {
+            fs::create_dir_all(&self.config_path)?;
+        }
+        let mut written = Vec::new();
+        for (path, content) in self.config_files() {
+            fs::write(&path, content)?;
+            written.push(path);
+        }
+        if self.writes_env() {
+            let env = self.env_content(&read(&self.env_path)?);
+            fs::write(&self.env_path, env)?;
+            written.push(self.env_path.clone());
+            let example_path = self.example_path();
+            if self.writes_example()
+                && let Some(example) = merge_env_example(&read(&example_path)?, &self.env_prefix)
+            {
+                fs::write(&example_path, example)?;
+                written.push(example_path);
+            }
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(content: &str) -> Vec<&str> {
+        content.lines().filter_map(env_line_key).collect()
+    }
+
+    #[test]
+    fn merge_env_rewrites_export_lines_in_place() {
+        let existing = "export STORAGE_INSTANCE_URL=old:50051\nexport OTHER=1\n";
+        let values = [
+            ("STORAGE_INSTANCE_URL", "new:50051"),
+            ("STORAGE_USER", "admin"),
+        ];
+        let merged = merge_env(existing, "", &values);
+        assert!(merged.starts_with("export STORAGE_INSTANCE_URL=new:50051\nexport OTHER=1\n"));
+        assert_eq!(
+            env_file_value(&merged, "STORAGE_USER").as_deref(),
+            Some("admin")
+        );
+    }
+
+    #[test]
+    fn merge_env_rerun_on_export_style_env_leaves_no_duplicates() {
+        let existing = "export STORAGE_INSTANCE_URL=old:50051\nexport STORAGE_USER=me\n";
+        let values = [
+            ("STORAGE_INSTANCE_URL", "new:50051"),
+            ("STORAGE_USER", "admin"),
+            ("STORAGE_AUTH_TOKEN", "secret"),
+        ];
+        let once = merge_env(existing, "", &values);
+        let twice = merge_env(&once, "", &values);
+        assert_eq!(once, twice);
+        let mut found = keys(&twice);
+        let total = found.len();
+        found.sort_unstable();
+        found.dedup();
+        assert_eq!(found.len(), total, "duplicate keys in {:?}", twice);
+    }
+
+    fn host_port(input: &str) -> (String, Option<u16>) {
+        parse_host_port(input).unwrap()
+    }
+
+    fn host_port_error(input: &str) -> String {
+        parse_host_port(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parse_host_port_splits_host_and_port() {
+        assert_eq!(
+            host_port("localhost:50051"),
+            ("localhost".into(), Some(50051))
+        );
+        assert_eq!(
+            host_port("  rice.example.com  "),
+            ("rice.example.com".into(), None)
+        );
+        assert_eq!(
+            host_port("https://rice.example.com:8443/health"),
+            ("rice.example.com".into(), Some(8443))
+        );
+        assert_eq!(host_port("http://rice/health"), ("rice".into(), None));
+        assert_eq!(
+            host_port("HTTPS://Rice.example.com"),
+            ("Rice.example.com".into(), None)
+        );
+    }
+
+    #[test]
+    fn parse_host_port_rejects_bad_input() {
+        assert_eq!(
+            host_port_error("localhost:65536"),
+            "'65536' is not a valid port"
+        );
+        assert_eq!(host_port_error("localhost:0"), "'0' is not a valid port");
+        assert_eq!(host_port_error(""), "missing host");
+        assert_eq!(host_port_error(":50051"), "missing host");
+        assert_eq!(
+            host_port_error("grpc://localhost:50051"),
+            "unsupported scheme 'grpc', use http or https"
+        );
+    }
+
+    #[test]
+    fn parse_host_port_accepts_ipv6() {
+        assert_eq!(host_port("[::1]"), ("::1".into(), None));
+        assert_eq!(
+            host_port("[fe80::1]:50051"),
+            ("fe80::1".into(), Some(50051))
+        );
+        assert_eq!(host_port("::1"), ("::1".into(), None));
+        assert_eq!(
+            host_port("http://[::1]:3000/health"),
+            ("::1".into(), Some(3000))
+        );
+        assert_eq!(
+            host_port("[::ffff:192.0.2.1]:50051"),
+            ("::ffff:192.0.2.1".into(), Some(50051))
+        );
+    }
+
+    #[test]
+    fn parse_host_port_rejects_bad_ipv6() {
+        assert_eq!(
+            host_port_error("::1:50051"),
+            "IPv6 addresses with a port must be bracketed, e.g. [::1]:50051"
+        );
+        assert_eq!(
+            host_port_error("fe80::1::50051"),
+            "IPv6 addresses with a port must be bracketed, e.g. [::1]:50051"
+        );
+        assert_eq!(
+            host_port_error("http://::1:3000"),
+            "IPv6 addresses in URLs must be bracketed, e.g. http://[::1]:3000"
+        );
+        assert_eq!(host_port_error("[::1"), "missing ']' after IPv6 address");
+        assert_eq!(host_port_error("[::1]50051"), "expected ':' after ']'");
+        assert_eq!(
+            host_port_error("[not-ipv6]:1"),
+            "'not-ipv6' is not a valid IPv6 address"
+        );
+    }
+
+    #[test]
+    fn url_http_port_defaults_to_the_scheme_port() {
+        assert_eq!(url_http_port("http://rice.example.com"), Some(80));
+        assert_eq!(url_http_port("https://rice.example.com/"), Some(443));
+        assert_eq!(url_http_port("HTTPS://rice.example.com:8443"), Some(8443));
+        assert_eq!(url_http_port("rice.example.com:50051"), None);
+        assert_eq!(url_http_port("not a url"), None);
+    }
+
+    #[test]
+    fn reconcile_http_port_keeps_the_url_port() {
+        assert_eq!(
+            reconcile_http_port("http://rice:3000", "3000"),
+            ("3000".to_string(), None)
+        );
+        assert_eq!(
+            reconcile_http_port("http://rice:3000", ""),
+            (String::new(), None)
+        );
+        assert_eq!(
+            reconcile_http_port("rice:50051", "3000"),
+            ("3000".to_string(), None)
+        );
+        let (port, warning) = reconcile_http_port("http://rice:8080", "3000");
+        assert_eq!(port, "8080");
+        assert_eq!(
+            warning.as_deref(),
+            Some(
+                "Instance URL http://rice:8080 already sets HTTP port 8080, so port 3000 is ignored"
+            )
+        );
+        let (port, warning) = reconcile_http_port("https://rice.example.com", " 3000 ");
+        assert_eq!(port, "443");
+        assert_eq!(
+            warning.as_deref(),
+            Some(
+                "Instance URL https://rice.example.com already sets HTTP port 443, so port 3000 is ignored"
+            )
+        );
+    }
+
+    #[test]
+    fn env_file_values_keep_only_set_tokens_next_to_toml() {
+        let values = [
+            ("STORAGE_INSTANCE_URL", "localhost:50051"),
+            ("STORAGE_AUTH_TOKEN", "tok123"),
+            ("STATE_AUTH_TOKEN", ""),
+        ];
+        assert_eq!(ConfigFormat::Js.env_file_values(&values), values);
+        assert_eq!(
+            ConfigFormat::Toml.env_file_values(&values),
+            [("STORAGE_AUTH_TOKEN", "tok123")]
+        );
+        let toml = ConfigFormat::Toml.render(ModuleFormat::Cjs, true, false, &values);
+        assert!(!toml.contains("tok123"), "{}", toml);
+    }
+
+    #[test]
+    fn quote_env_value_quotes_only_when_needed() {
+        let cases = [
+            ("plain-token_1.2", "plain-token_1.2"),
+            ("", ""),
+            ("has space", "\"has space\""),
+            ("a#b", "\"a#b\""),
+            ("k=v", "\"k=v\""),
+            ("say \"hi\"", "\"say \\\"hi\\\"\""),
+            ("it's", "\"it's\""),
+            ("back\\slash", "\"back\\\\slash\""),
+            ("$HOME", "\"\\$HOME\""),
+            ("two\nlines", "\"two\\nlines\""),
+        ];
+        for (value, quoted) in cases {
+            assert_eq!(quote_env_value(value), quoted, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn quote_env_value_round_trips_through_dotenv() {
+        for value in [
+            "has space",
+            "a#b",
+            "say \"hi\"",
+            "back\\slash",
+            "$HOME",
+            "two\nlines",
+        ] {
+            let line = format!("KEY={}", quote_env_value(value));
+            let parsed: Vec<(String, String)> = dotenvy::from_read_iter(line.as_bytes())
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(parsed, [("KEY".to_string(), value.to_string())], "{}", line);
+        }
+    }
+
+    #[test]
+    fn validate_run_id_accepts_the_documented_characters() {
+        for run_id in [
+            "default",
+            "run-1",
+            "agent_2.v3",
+            "  padded  ",
+            &"a".repeat(64),
+        ] {
+            assert!(validate_run_id(run_id).is_ok(), "{:?}", run_id);
+        }
+        let cases = [
+            ("", "run ID cannot be empty"),
+            ("   ", "run ID cannot be empty"),
+            (
+                &"a".repeat(65),
+                "run ID is 65 characters long, the limit is 64",
+            ),
+            (
+                "my run",
+                "run ID may only contain letters, digits, '.', '_' and '-', not ' '",
+            ),
+            (
+                "run/1",
+                "run ID may only contain letters, digits, '.', '_' and '-', not '/'",
+            ),
+        ];
+        for (run_id, error) in cases {
+            assert_eq!(validate_run_id(run_id).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn iso8601_utc_formats_civil_dates() {
+        let at =
+            |secs: u64| iso8601_utc(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        let cases = [
+            (0, "1970-01-01T00:00:00Z"),
+            (951_782_400, "2000-02-29T00:00:00Z"),
+            (1_709_251_199, "2024-02-29T23:59:59Z"),
+            (1_714_566_896, "2024-05-01T12:34:56Z"),
+            (4_107_542_400, "2100-03-01T00:00:00Z"),
+        ];
+        for (secs, formatted) in cases {
+            assert_eq!(at(secs), formatted, "{}", secs);
+        }
+        let before_epoch = std::time::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert_eq!(iso8601_utc(before_epoch), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn env_reference_matches_whole_references_only() {
+        let cases = [
+            ("${CI_RICE_TOKEN}", Some("CI_RICE_TOKEN")),
+            ("  ${_TOKEN2}  ", Some("_TOKEN2")),
+            ("${}", None),
+            ("${1TOKEN}", None),
+            ("${BAD-NAME}", None),
+            ("$CI_RICE_TOKEN", None),
+            ("prefix-${TOKEN}", None),
+            ("${TOKEN}-suffix", None),
+            ("plain", None),
+        ];
+        for (value, name) in cases {
+            assert_eq!(env_reference(value), name, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn lost_config_keys_lists_dropped_keys_once() {
+        let generated =
+            "module.exports = {\n  storage: { enabled: true },\n  state: { enabled: false },\n};\n";
+        let existing = "module.exports = {\n  storage: { enabled: true, pool: { size: 4, idle: 10 } },\n  state: { enabled: false }, // tracing: { on: true }\n  tracing: { on: true },\n  name: 'a: {b}',\n};\n";
+        assert_eq!(
+            lost_config_keys(existing, generated, ConfigFormat::Js),
+            ["storage.pool", "tracing", "name"]
+        );
+        assert!(lost_config_keys(generated, generated, ConfigFormat::Js).is_empty());
+
+        let generated = "[rice.storage]\nenabled = true\n";
+        let existing = "[rice.storage]\nenabled = true\nuser = \"admin\"\n\n[extra]\nkey = 1\n";
+        assert_eq!(
+            lost_config_keys(existing, generated, ConfigFormat::Toml),
+            ["extra", "rice.storage.user"]
+        );
+    }
+
+    #[test]
+    fn validate_js_config_skips_commented_out_sections() {
+        let content = "module.exports = {\n  // storage: { enabled: true },\n  state: { enabled: false },\n};\n";
+        assert_eq!(
+            validate_config(content, ConfigFormat::Js),
+            Err(vec!["missing `storage` section".to_string()])
+        );
+        let content = "module.exports = {\n  /* state: { enabled: true }, */\n  storage: { enabled: true },\n  state: { enabled: false },\n};\n";
+        assert_eq!(
+            validate_config(content, ConfigFormat::Js),
+            Ok((true, false))
+        );
+    }
+
+    #[test]
+    fn validate_js_config_ignores_braces_in_strings() {
+        let content = "module.exports = {\n  name: '{',\n  url: \"https://example.com/}\",\n  storage: { enabled: true, note: 'state: { enabled: true }' },\n  state: { enabled: false },\n};\n";
+        assert_eq!(
+            validate_config(content, ConfigFormat::Js),
+            Ok((true, false))
+        );
+        let content = "// module.exports = {}\nconst config = { storage: { enabled: true }, state: { enabled: true } };\n";
+        assert_eq!(
+            validate_config(content, ConfigFormat::Js),
+            Err(vec![
+                "no `module.exports` or `export default` found".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn strip_rice_env_example_removes_the_merged_entries() {
+        let existing = "APP_PORT=8080\n";
+        let example = merge_env_example(existing, "").unwrap();
+        assert_eq!(
+            strip_rice_env_example(&example, ""),
+            (existing.to_string(), example.lines().count() - 2)
+        );
+        let example = merge_env_example("", "").unwrap();
+        assert_eq!(strip_rice_env_example(&example, "").0, "");
+    }
+
+    #[test]
+    fn export_lines_count_as_rice_lines() {
+        assert!(is_rice_env_line("export STORAGE_AUTH_TOKEN=x", ""));
+        assert!(is_rice_env_line("export APP_STATE_RUN_ID=x", "APP_"));
+        assert!(!is_rice_env_line("export OTHER=x", ""));
+        assert!(env_has_key("export STORAGE_USER=x\n", "STORAGE_USER"));
+    }
+}
@@ -1,14 +1,197 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use console::{Emoji, style};
 use dialoguer::{Confirm, Input, Password, theme::ColorfulTheme};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::debug;
 use reqwest::Client;
+use rice_cli::{
+    BUNDLE_FIELDS, Backoff, CONFIG_DIR, CONFIG_FILE_NAMES, ConfigFormat, DEFAULT_MAX_REDIRECTS,
+    DEFAULT_TIMEOUT_SECS, Endpoint, EnvMode, GrpcStatus, HealthCriteria, HealthReport,
+    LOCAL_ENV_FILE, MIN_SERVER_VERSION, ModuleFormat, PackageManager, Polling, RICE_ENV_KEYS,
+    RequestFailure, RiceConfig, RiceError, RiceSetup, SECRET_ENV_KEYS, Scheme, SetupTemplate,
+    StateConfig, StateTarget, StorageConfig, ValueSource, backup_file, check_health,
+    check_reachable, default_env_value, docker_host_url, env_file_value, env_prefix, env_reference,
+    find_package_json, find_ricerc, foreign_env_values, gitignore_ignores, health_request,
+    in_container, is_placeholder_token, is_rice_env_line, iso8601_utc, lost_config_keys,
+    mask_env_line, mask_secret, merge_env, merge_env_example, parse_env_bool, parse_version,
+    probe_grpc, quote_env_value, reconcile_http_port, remove_config_dir, resolve_host,
+    ricerc_values, root_cause, server_version, shell_quote, strip_rice_env, strip_rice_env_example,
+    toml_env_values, url_http_port, validate_config_name, validate_config_path,
+    validate_env_prefix, validate_env_value, validate_http_port, validate_instance_url,
+    validate_optional_http_port, validate_run_id, validate_state_url,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 static CHECK: Emoji<'_, '_> = Emoji("✔  ", "");
 static CROSS: Emoji<'_, '_> = Emoji("✖  ", "");
+static WARN: Emoji<'_, '_> = Emoji("⚠  ", "");
+
+/// Whether spinners are drawn, set once at startup: not with `--no-spinner`
+/// or when output is not a terminal, as in CI logs.
+static SPINNERS: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+/// The Rice variables whose value is a `${NAME}` reference, with the name,
+/// recorded by `GlobalArgs::load_env` before it resolves them. Replaced with
+/// [`LOADED_ENV`] on every load, so `reset` sees the files it rewrote.
+static ENV_REFERENCES: std::sync::Mutex<Vec<(&'static str, String)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Variables loaded by `GlobalArgs::load_env`, over the process
+/// environment; `None` hides a process variable, as the plain Rice names
+/// are under an env prefix. Read them with [`env_var`].
+static LOADED_ENV: std::sync::Mutex<BTreeMap<String, Option<String>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+/// A variable as the CLI sees it: loaded from the env files and config,
+/// else from the process environment.
+fn env_var(var: &str) -> Option<String> {
+    match LOADED_ENV
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(var)
+    {
+        Some(value) => value.clone(),
+        None => std::env::var(var).ok(),
+    }
+}
+
+/// Finds `name` in the current directory or the nearest parent that has
+/// it, as dotenvy does for `.env`.
+fn find_env_file(name: &str) -> Option<PathBuf> {
+    std::env::current_dir()
+        .ok()?
+        .ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The variable `var` was read from through a `${NAME}` reference, if any.
+fn env_reference_of(var: &str) -> Option<String> {
+    ENV_REFERENCES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(referenced, _)| *referenced == var)
+        .map(|(_, name)| name.clone())
+}
+
+/// Resolves a `${NAME}` value from the environment, empty when `NAME` is
+/// unset. Other values pass through.
+fn expand_env_reference(value: String) -> String {
+    match env_reference(&value) {
+        Some(name) => env_var(name).unwrap_or_default(),
+        None => value,
+    }
+}
+
+static VERBOSITY: std::sync::OnceLock<Verbosity> = std::sync::OnceLock::new();
+
+/// How much human-readable output to print.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// Sets the verbosity once for the whole process. `--verbose` also turns on
+/// the library's `log` diagnostics, printed dimmed to stderr.
+fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.set(verbosity).ok();
+    if verbosity == Verbosity::Verbose && log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+}
+
+/// The verbosity set by [`set_verbosity`], [`Verbosity::Normal`] by default.
+fn verbosity() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or(Verbosity::Normal)
+}
+
+/// Prints this crate's debug records; those of dependencies such as
+/// reqwest are dropped.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("rice_cli")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", style(record.args()).dim());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn spinners() -> bool {
+    SPINNERS.get().copied().unwrap_or(true)
+}
+
+/// Service name under which `--secret-store keyring` saves tokens.
+const KEYRING_SERVICE: &str = "rice-cli";
+
+/// Exit codes, listed in the `--help` of the commands that use them.
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  Success
+  1  Other error
+  2  Invalid command-line usage
+  3  Rice could not be reached (connection refused, timeout, DNS)
+  4  Rice answered but is unhealthy
+  5  Rice configuration is missing or invalid";
+
+/// Prints decorative output (headings, hints, spacing); `--quiet` hides it.
+/// Results, warnings and errors use plain `println!`/`eprintln!`.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Normal {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// What went wrong, so scripts can tell failures apart by exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FailureKind {
+    Unreachable,
+    Unhealthy,
+    Config,
+}
+
+impl FailureKind {
+    /// Classifies a failed probe: no status at all means the service could
+    /// not be reached, while a status means it answered but is unhealthy.
+    fn of(report: &HealthReport) -> Option<FailureKind> {
+        match (report.healthy, report.status_code) {
+            (true, _) => None,
+            (false, None) => Some(FailureKind::Unreachable),
+            (false, Some(_)) => Some(FailureKind::Unhealthy),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            FailureKind::Unreachable => "unreachable",
+            FailureKind::Unhealthy => "unhealthy",
+            FailureKind::Config => "config",
+        }
+    }
+
+    fn error(self, message: impl Into<String>) -> RiceError {
+        let message = message.into();
+        match self {
+            FailureKind::Unreachable => RiceError::Unreachable(message),
+            FailureKind::Unhealthy => RiceError::Unhealthy(message),
+            FailureKind::Config => RiceError::Config(message),
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(name = "rice-cli")]
@@ -16,287 +199,4199 @@ static CROSS: Emoji<'_, '_> = Emoji("✖  ", "");
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[command(flatten)]
+    global: GlobalArgs,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Setup Rice in the current project (default)
-    Setup,
-    /// Show current configuration
-    Config,
-    /// Check connection to Rice instance
-    Check,
+/// Options accepted by every subcommand.
+#[derive(Args)]
+struct GlobalArgs {
+    /// Run as if started in DIR; relative paths are resolved against it
+    #[arg(long, global = true, value_name = "DIR", value_parser = parse_cwd)]
+    cwd: Option<PathBuf>,
+    /// Output format for results
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    /// Indent JSON output for reading instead of printing one line
+    #[arg(long, global = true)]
+    json_pretty: bool,
+    /// Named environment; uses .env.<PROFILE> instead of .env
+    #[arg(long, global = true, value_parser = parse_profile)]
+    profile: Option<String>,
+    /// Env file to read and write instead of .env
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "profile")]
+    env_file: Option<PathBuf>,
+    /// Rice config file to read and write instead of rice.config.{js,cjs,mjs,json,toml}
+    #[arg(long, global = true, value_name = "PATH")]
+    config_file: Option<PathBuf>,
+    /// Prefix for the Rice variable names, e.g. APP_ for APP_STORAGE_INSTANCE_URL;
+    /// defaults to the prefix recorded in the env file
+    #[arg(long, global = true, value_name = "PREFIX", value_parser = parse_env_prefix)]
+    env_prefix: Option<String>,
+    /// Only print results and errors
+    #[arg(long, short = 'q', global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print resolved URLs, response statuses and timings
+    #[arg(long, short = 'v', global = true)]
+    verbose: bool,
+    /// Print progress as plain lines instead of animated spinners
+    #[arg(long, global = true)]
+    no_spinner: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+impl GlobalArgs {
+    /// Whether results are printed as JSON or YAML rather than text.
+    fn structured(&self) -> bool {
+        self.output != OutputFormat::Text
+    }
 
-    match cli.command {
-        Some(Commands::Setup) | None => run_setup().await?,
-        Some(Commands::Config) => run_config()?,
-        Some(Commands::Check) => run_check().await?,
+    /// Prints `report` on stdout in the `--output` format: JSON, indented
+    /// with `--json-pretty`, or a YAML document.
+    fn print_report(&self, report: &impl Serialize) -> Result<(), RiceError> {
+        let output = match self.output {
+            OutputFormat::Yaml => {
+                let yaml =
+                    serde_yaml::to_string(report).map_err(|e| RiceError::Other(e.to_string()))?;
+                format!("---\n{}", yaml.trim_end())
+            }
+            _ if self.json_pretty => serde_json::to_string_pretty(report)?,
+            _ => serde_json::to_string(report)?,
+        };
+        println!("{}", output);
+        Ok(())
+    }
+
+    fn env_path(&self) -> PathBuf {
+        if let Some(path) = &self.env_file {
+            return path.clone();
+        }
+        match &self.profile {
+            Some(profile) => PathBuf::from(format!(".env.{}", profile)),
+            None => PathBuf::from(".env"),
+        }
     }
-    Ok(())
-}
 
-async fn run_setup() -> Result<(), Box<dyn std::error::Error>> {
-    println!("{}", style("Welcome to the Rice CLI Setup").bold().green());
-    println!("This utility will walk you through setting up Rice in your project.\n");
+    /// Finds the Rice config file: the `--config-file` path if given,
+    /// otherwise the first of [`CONFIG_FILE_NAMES`] in the current
+    /// directory, else a [`CONFIG_DIR`] of fragments.
+    fn locate_config(&self) -> Option<(PathBuf, ConfigFormat)> {
+        match &self.config_file {
+            Some(path) => path
+                .exists()
+                .then(|| (path.clone(), ConfigFormat::from_path(path))),
+            None => CONFIG_FILE_NAMES
+                .into_iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists())
+                .map(|path| {
+                    let format = ConfigFormat::from_path(&path);
+                    (path, format)
+                })
+                .or_else(|| {
+                    Path::new(CONFIG_DIR)
+                        .is_dir()
+                        .then(|| (PathBuf::from(CONFIG_DIR), ConfigFormat::Js))
+                }),
+        }
+    }
 
-    let theme = ColorfulTheme::default();
+    /// The `--env-prefix`, or the one setup recorded in the env file.
+    fn env_prefix(&self) -> String {
+        self.env_prefix.clone().unwrap_or_else(|| {
+            let content = fs::read_to_string(self.env_path()).unwrap_or_default();
+            env_prefix(&content).to_string()
+        })
+    }
 
-    // 1. Configuration Questions
-    let enable_storage = Confirm::with_theme(&theme)
-        .with_prompt("Enable Rice Storage?")
-        .default(true)
-        .interact()?;
+    fn config_not_found(&self) -> String {
+        match &self.config_file {
+            Some(path) => format!("{} not found", path.display()),
+            None => format!(
+                "No rice.config.js, .cjs, .mjs, .json or .toml (or {}/) found",
+                CONFIG_DIR
+            ),
+        }
+    }
+
+    /// Loads the profile's env file, then any connection values in a TOML
+    /// config file, into [`LOADED_ENV`]; the process environment itself is
+    /// never modified. Variables already set take precedence. With an env
+    /// prefix, the prefixed variables replace the plain Rice names, which the
+    /// rest of the CLI reads. Values that are exactly `${NAME}` are then
+    /// resolved, wherever they came from. Returns the variables taken from
+    /// the config file.
+    fn load_env(&self) -> Vec<&'static str> {
+        let mut references = self.raw_env_references();
+        let env_files = if self.profile.is_some() || self.env_file.is_some() {
+            vec![Some(self.env_path())]
+        } else {
+            // Earlier files win, so .env.local overrides .env.
+            vec![find_env_file(LOCAL_ENV_FILE), find_env_file(".env")]
+        };
+        let mut env = BTreeMap::new();
+        for path in env_files.into_iter().flatten() {
+            let Ok(iter) = dotenvy::from_path_iter(&path) else {
+                debug!("No env file loaded from {}", path.display());
+                continue;
+            };
+            for (var, value) in iter.filter_map(Result::ok) {
+                if std::env::var_os(&var).is_none() && !env.contains_key(&var) {
+                    env.insert(var, Some(value));
+                }
+            }
+            debug!("Loaded environment from {}", path.display());
+        }
+        let get = |env: &BTreeMap<String, Option<String>>, var: &str| match env.get(var) {
+            Some(value) => value.clone(),
+            None => std::env::var(var).ok(),
+        };
+        let prefix = self.env_prefix();
+        if !prefix.is_empty() {
+            debug!("Reading Rice variables as {}<NAME>", prefix);
+            for var in RICE_ENV_KEYS {
+                let value = get(&env, &format!("{}{}", prefix, var));
+                env.insert(var.to_string(), value);
+            }
+        }
 
-    let enable_state = Confirm::with_theme(&theme)
-        .with_prompt("Enable Rice State (AI Agent Memory)?")
-        .default(true)
-        .interact()?;
+        let mut from_config = Vec::new();
+        if let Some((path, ConfigFormat::Toml)) = self.locate_config() {
+            let content = fs::read_to_string(&path).unwrap_or_default();
+            for (var, value) in toml_env_values(&content) {
+                if get(&env, var).is_none() {
+                    env.insert(var.to_string(), Some(value));
+                    from_config.push(var);
+                }
+            }
+            if !from_config.is_empty() {
+                debug!("Loaded {} from {}", from_config.join(", "), path.display());
+            }
+        }
 
-    if !enable_storage && !enable_state {
-        println!("{}", style("You must enable at least one service.").red());
-        return Ok(());
+        for var in RICE_ENV_KEYS {
+            if let Some(name) = get(&env, var).as_deref().and_then(env_reference)
+                && !references.iter().any(|(referenced, _)| *referenced == var)
+            {
+                references.push((var, name.to_string()));
+            }
+        }
+        for (var, name) in &references {
+            let value = get(&env, name).unwrap_or_default();
+            debug!("Resolved {} from ${{{}}}", var, name);
+            env.insert(var.to_string(), Some(value));
+        }
+        *LOADED_ENV.lock().unwrap_or_else(|e| e.into_inner()) = env;
+        *ENV_REFERENCES.lock().unwrap_or_else(|e| e.into_inner()) = references;
+        from_config
     }
 
-    // Storage Config
-    let mut storage_url = String::from("localhost:50051");
-    let mut storage_user = String::from("admin");
-    let mut storage_token = String::new();
-    let mut storage_http_port = String::from("3000");
+    /// The Rice variables whose raw value, in the process environment or
+    /// an env file, is a `${NAME}` reference. dotenvy substitutes these
+    /// itself, so they are read before loading.
+    fn raw_env_references(&self) -> Vec<(&'static str, String)> {
+        let env_files = if self.profile.is_some() || self.env_file.is_some() {
+            vec![self.env_path()]
+        } else {
+            vec![PathBuf::from(LOCAL_ENV_FILE), self.env_path()]
+        };
+        let contents: Vec<String> = env_files
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap_or_default())
+            .collect();
+        let prefix = self.env_prefix();
+        RICE_ENV_KEYS
+            .into_iter()
+            .filter_map(|var| {
+                let key = format!("{}{}", prefix, var);
+                let value = std::env::var(&key).ok().or_else(|| {
+                    contents
+                        .iter()
+                        .find_map(|content| env_file_value(content, &key))
+                })?;
+                env_reference(&value).map(|name| (var, name.to_string()))
+            })
+            .collect()
+    }
 
-    if enable_storage {
-        println!("\n{}", style("Storage Configuration").bold());
+    /// Variables set by .env.local, which is only read without `--profile`
+    /// and `--env-file`.
+    fn local_env_vars(&self) -> Vec<String> {
+        if self.profile.is_some() || self.env_file.is_some() {
+            return Vec::new();
+        }
+        let prefix = self.env_prefix();
+        dotenvy::from_filename_iter(LOCAL_ENV_FILE)
+            .map(|iter| {
+                iter.filter_map(Result::ok)
+                    .filter_map(|(var, _)| var.strip_prefix(&prefix).map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 
-        storage_url = Input::with_theme(&theme)
-            .with_prompt("Storage Instance URL")
-            .default("localhost:50051".into())
-            .interact_text()?;
+    /// The keyring entry for a secret of this env file. The account includes
+    /// the env file's absolute path so projects and profiles never share
+    /// tokens.
+    fn keyring_entry(&self, var: &str) -> keyring::Result<keyring::Entry> {
+        let env_path = self.env_path();
+        let env_path = std::path::absolute(&env_path).unwrap_or(env_path);
+        keyring::Entry::new(KEYRING_SERVICE, &format!("{}:{}", env_path.display(), var))
+    }
 
-        storage_user = Input::with_theme(&theme)
-            .with_prompt("Storage User")
-            .default("admin".into())
-            .interact_text()?;
+    /// Reads a Rice variable from the environment, except that secrets held
+    /// in the OS keyring take precedence.
+    fn lookup(&self, var: &str) -> Option<String> {
+        SECRET_ENV_KEYS
+            .contains(&var)
+            .then(|| {
+                self.keyring_entry(var)
+                    .and_then(|entry| entry.get_password())
+            })
+            .and_then(Result::ok)
+            .map(expand_env_reference)
+            .or_else(|| env_var(var))
+    }
 
-        storage_token = Password::with_theme(&theme)
-            .with_prompt("Storage Auth Token/Password")
-            .allow_empty_password(true)
-            .interact()?;
+    /// Like `lookup`, with a missing secret read as empty.
+    fn secret(&self, var: &str) -> String {
+        self.lookup(var).unwrap_or_default()
+    }
 
-        storage_http_port = Input::with_theme(&theme)
-            .with_prompt("Storage HTTP Port (for verification)")
-            .default("3000".into())
-            .interact_text()?;
+    fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
     }
+}
 
-    // State Config
-    let mut state_url = String::from("localhost:50051");
-    let mut state_token = String::new();
-    let mut state_run_id = String::from("default");
+fn parse_cwd(dir: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(dir);
+    if path.is_dir() {
+        Ok(path)
+    } else if path.exists() {
+        Err(format!("{} is not a directory", dir))
+    } else {
+        Err(format!("{} does not exist", dir))
+    }
+}
 
-    if enable_state {
-        println!("\n{}", style("State Configuration").bold());
+fn parse_env_prefix(prefix: &str) -> Result<String, RiceError> {
+    validate_env_prefix(prefix).map(|()| prefix.to_string())
+}
 
-        state_url = Input::with_theme(&theme)
-            .with_prompt("State Instance URL")
-            .default("localhost:50051".into())
-            .interact_text()?;
+fn parse_config_name(name: &str) -> Result<String, RiceError> {
+    validate_config_name(name).map(|()| name.to_string())
+}
+
+fn parse_profile(name: &str) -> Result<String, String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(name.to_string())
+    } else {
+        Err("profile names may only contain letters, digits, '-' and '_'".into())
+    }
+}
+
+/// The services `check` probes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ServiceSelection {
+    Storage,
+    State,
+    #[default]
+    All,
+}
 
-        state_token = Password::with_theme(&theme)
-            .with_prompt("State Auth Token")
-            .allow_empty_password(true)
-            .interact()?;
+impl ServiceSelection {
+    fn includes(self, service: &str) -> bool {
+        match self {
+            ServiceSelection::Storage => service == "storage",
+            ServiceSelection::State => service == "state",
+            ServiceSelection::All => true,
+        }
+    }
 
-        state_run_id = Input::with_theme(&theme)
-            .with_prompt("State Run ID")
-            .default("default".into())
-            .interact_text()?;
+    /// The reports of the selected services, for JSON output.
+    fn filter(self, reports: [ServiceReport; 2]) -> Vec<ServiceReport> {
+        reports
+            .into_iter()
+            .filter(|report| self.includes(report.service))
+            .collect()
     }
+}
 
-    // 2. Generate rice.config.js
-    println!("\n{}", style("Generating configuration files...").bold());
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// Machine-readable JSON on stdout
+    Json,
+    /// Machine-readable YAML on stdout
+    Yaml,
+}
 
-    let config_content = format!(
-        "/** @type {{import('rice-node-sdk').RiceConfig}} */\nmodule.exports = {{\n  storage: {{\n    enabled: {},\n  }},\n  state: {{\n    enabled: {},\n  }},\n}};",
-        enable_storage, enable_state
-    );
+#[derive(Subcommand)]
+enum Commands {
+    /// Setup Rice in the current project (default)
+    #[command(after_help = EXIT_CODES_HELP)]
+    Setup(Box<SetupArgs>),
+    /// Show current configuration
+    Config(ConfigArgs),
+    /// List the profiles (.env.<name> files) in the current directory
+    Profiles,
+    /// Check connection to Rice instance
+    #[command(after_help = EXIT_CODES_HELP)]
+    Check(CheckArgs),
+    /// Check the connection every few seconds until Ctrl-C (check --watch)
+    Ping(CheckArgs),
+    /// Show both services and config state at a glance
+    #[command(after_help = EXIT_CODES_HELP)]
+    Status {
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+    /// Check the Rice config file structure without connecting
+    Validate,
+    /// Diagnose common setup problems
+    Doctor {
+        /// Print only a final summary line (a JSON object with --output json)
+        #[arg(long)]
+        summary_only: bool,
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+    /// Compare the Rice server version with the versions this CLI supports
+    Version {
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+    /// Write the Rice configuration to a JSON bundle for sharing
+    Export {
+        /// File to write the bundle to
+        path: PathBuf,
+        /// Include auth tokens in the bundle
+        #[arg(long)]
+        include_secrets: bool,
+    },
+    /// Apply an exported JSON bundle without prompting
+    Import {
+        /// Bundle written by `export`
+        path: PathBuf,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Remove the Rice configuration, then run setup from a clean slate
+    #[command(after_help = EXIT_CODES_HELP)]
+    Reset(Box<SetupArgs>),
+    /// Remove the Rice config file and the Rice variables from .env
+    Uninstall {
+        /// Remove without asking for confirmation
+        #[arg(long, short = 'y')]
+        yes: bool,
+    },
+}
+
+#[derive(Args, Default)]
+struct SetupArgs {
+    /// Skip all prompts and read answers from flags (for CI)
+    #[arg(long, short = 'y', visible_alias = "yes")]
+    non_interactive: bool,
+    /// Enable Rice Storage
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    enable_storage: Option<bool>,
+    /// Enable Rice State
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    enable_state: Option<bool>,
+    /// Storage instance URL
+    #[arg(long)]
+    storage_url: Option<String>,
+    /// Storage user
+    #[arg(long)]
+    storage_user: Option<String>,
+    /// Storage auth token/password
+    #[arg(long)]
+    storage_token: Option<String>,
+    /// Read the Storage auth token from a file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["storage_token", "storage_token_stdin"])]
+    storage_token_file: Option<PathBuf>,
+    /// Read the Storage auth token from stdin
+    #[arg(long, conflicts_with_all = ["storage_token", "state_token_stdin"])]
+    storage_token_stdin: bool,
+    /// Storage HTTP port (for verification)
+    #[arg(long)]
+    storage_http_port: Option<String>,
+    /// State instance URL
+    #[arg(long)]
+    state_url: Option<String>,
+    /// State auth token
+    #[arg(long)]
+    state_token: Option<String>,
+    /// Read the State auth token from a file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["state_token", "state_token_stdin"])]
+    state_token_file: Option<PathBuf>,
+    /// Read the State auth token from stdin
+    #[arg(long, conflicts_with = "state_token")]
+    state_token_stdin: bool,
+    /// State run ID
+    #[arg(long)]
+    state_run_id: Option<String>,
+    /// State HTTP port (for verification; empty checks the gRPC port)
+    #[arg(long)]
+    state_http_port: Option<String>,
+    /// Seed the prompt defaults (and non-interactive answers) from a preset
+    #[arg(long, value_enum)]
+    template: Option<SetupTemplate>,
+    /// Config file format to generate [default: from --config-file, --config-name or the existing config file, else js]
+    #[arg(long, value_enum)]
+    config_format: Option<ConfigFormat>,
+    /// File name of the config file to create in the current directory, e.g. rice.config.cjs [default: the existing config file, else rice.config.js]
+    #[arg(long, value_name = "FILENAME", value_parser = parse_config_name, conflicts_with = "config_file")]
+    config_name: Option<String>,
+    /// Write storage and state to separate fragments in DIR (storage.js, state.js) with an index.js that merges them
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["config_file", "config_name", "config_format"]
+    )]
+    config_dir: Option<PathBuf>,
+    /// Module format for rice.config.js (detected from package.json by default)
+    #[arg(long, value_enum)]
+    module_format: Option<ModuleFormat>,
+    /// Where to store auth tokens
+    #[arg(long, value_enum, default_value_t = SecretStore::Env)]
+    secret_store: SecretStore,
+    /// Use auth tokens for verification only; write them to .env as empty values
+    #[arg(long)]
+    no_persist_token: bool,
+    /// Don't create or update .env.example
+    #[arg(long)]
+    no_example: bool,
+    /// Don't add .env to .gitignore, which -y otherwise does
+    #[arg(long)]
+    no_gitignore: bool,
+    /// Open the config file in $VISUAL or $EDITOR once it is written
+    #[arg(long, conflicts_with_all = ["non_interactive", "dry_run"])]
+    edit: bool,
+    /// How to write the Rice variables into an existing .env
+    #[arg(long, value_enum, default_value_t = EnvMode::Merge)]
+    env_mode: EnvMode,
+    /// Write to .env.local, which overrides .env, instead of .env
+    #[arg(long, conflicts_with_all = ["env_file", "profile"])]
+    local: bool,
+    /// Overwrite an existing config file without asking
+    #[arg(long)]
+    force: bool,
+    /// Don't back up .env and rice.config.js before modifying them
+    #[arg(long)]
+    no_backup: bool,
+    /// Show the file changes setup would make without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Poll verification every second until ready, giving up after SECS
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "60")]
+    wait: Option<u64>,
+    /// Skip the connection verification (e.g. when Rice isn't deployed yet)
+    #[arg(long, conflicts_with = "wait")]
+    no_verify: bool,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    /// Print secret values instead of masking them
+    #[arg(long)]
+    show_secrets: bool,
+    /// Print the variables as lines to source into a shell or .env file
+    #[arg(long, value_enum)]
+    format: Option<VarsFormat>,
+    /// Update one Rice variable in .env (repeatable); without a value, prompt for it
+    #[arg(long, value_name = "KEY[=VALUE]", conflicts_with = "format")]
+    set: Vec<String>,
+    /// Also run the health check for each enabled service
+    #[arg(long, conflicts_with_all = ["format", "set"])]
+    validate_connectivity: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VarsFormat {
+    /// Shell-quoted `KEY='value'` assignments
+    Env,
+    /// Shell-quoted `export KEY='value'` lines, for `eval`
+    Export,
+    /// Bare `KEY=value` lines in .env syntax
+    Dotenv,
+}
+
+#[derive(Args, Default)]
+struct CheckArgs {
+    /// Service to check
+    #[arg(value_enum, default_value_t = ServiceSelection::All)]
+    service: ServiceSelection,
+    /// Number of extra attempts after a failed health check
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    /// First delay between --retries attempts, doubled (with jitter) after each failure
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    backoff_base: u64,
+    /// Longest delay between --retries attempts
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    backoff_max: u64,
+    /// Poll every second until the services are ready, giving up after SECS
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        conflicts_with = "retries"
+    )]
+    wait: Option<u64>,
+    /// Also run a gRPC health check against the gRPC port (default 50051)
+    #[arg(long)]
+    grpc: bool,
+    /// HTTP status the Storage health endpoint, and State's when checked over
+    /// HTTP, must return [default: any 2xx]
+    #[arg(long, value_name = "CODE", value_parser = clap::value_parser!(u16).range(100..=599))]
+    expect_status: Option<u16>,
+    /// Comma-separated HTTP statuses that also count as healthy, e.g. 204,401
+    #[arg(
+        long,
+        value_name = "CODES",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u16).range(100..=599)
+    )]
+    assume_healthy_on: Vec<u16>,
+    /// Text the Storage health response body, and State's when checked over
+    /// HTTP, must contain
+    #[arg(long, value_name = "TEXT")]
+    expect_body_contains: Option<String>,
+    /// Re-run the check every --interval seconds until Ctrl-C
+    #[arg(long, conflicts_with_all = ["retries", "wait"])]
+    watch: bool,
+    /// Resolve each host before connecting and report DNS failures as such
+    #[arg(long)]
+    check_dns: bool,
+    /// Print nothing and rely on the exit code; on failure, write a JSON
+    /// summary to stderr (-v shows the usual output)
+    #[arg(long, conflicts_with = "watch")]
+    silent: bool,
+    /// Print only a final summary line (a JSON object with --output json)
+    #[arg(long, conflicts_with_all = ["watch", "silent"])]
+    summary_only: bool,
+    /// Seconds between checks with --watch
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: u64,
+    /// Append one timestamped JSON line per service result to PATH
+    /// [env: RICE_CHECK_LOG]
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+impl CheckArgs {
+    fn polling(&self) -> Polling {
+        let backoff = Backoff {
+            base: std::time::Duration::from_millis(self.backoff_base),
+            max: std::time::Duration::from_millis(self.backoff_max),
+        };
+        Polling::new(self.retries, self.wait, backoff)
+    }
+
+    /// The `--log-file` path, falling back to `RICE_CHECK_LOG`.
+    fn log_path(&self) -> Option<PathBuf> {
+        self.log_file.clone().or_else(|| {
+            env_var("RICE_CHECK_LOG")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+
+    /// What a Storage health response must look like to count as healthy.
+    fn health_criteria(&self) -> HealthCriteria {
+        HealthCriteria {
+            expect_status: self.expect_status,
+            assume_healthy_on: self.assume_healthy_on.clone(),
+            body_contains: self.expect_body_contains.clone(),
+        }
+    }
+}
+
+/// Options for the HTTP health request, shared by setup verification and check.
+#[derive(Args, Default)]
+struct HttpArgs {
+    /// Scheme for the health URL (overrides the one in the instance URL)
+    #[arg(long, value_enum)]
+    scheme: Option<Scheme>,
+    /// Connect and request timeout in seconds [default: 5]
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    /// Path of the Storage health endpoint [default: $STORAGE_HEALTH_PATH or /health]
+    #[arg(long, value_name = "PATH")]
+    health_path: Option<String>,
+    /// Proxy URL for health requests (overrides HTTP_PROXY/HTTPS_PROXY)
+    #[arg(long, value_name = "URL", conflicts_with = "no_proxy")]
+    proxy: Option<String>,
+    /// Connect directly, ignoring HTTP_PROXY/HTTPS_PROXY
+    #[arg(long)]
+    no_proxy: bool,
+    /// User-Agent header for health requests [default: rice-cli/<version>]
+    #[arg(long, value_name = "UA")]
+    user_agent: Option<String>,
+    /// Follow redirects from the health endpoint [default: true]
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    follow_redirects: Option<bool>,
+    /// Redirects to follow before giving up [default: 5]
+    #[arg(long, value_name = "N")]
+    max_redirects: Option<usize>,
+    /// Skip TLS certificate verification (for self-signed dev certificates)
+    #[arg(long, short = 'k')]
+    insecure: bool,
+    /// Extra request header, e.g. "X-Api-Key: secret" (repeatable)
+    #[arg(long = "header", short = 'H', value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(reqwest::header::HeaderName, reqwest::header::HeaderValue)>,
+}
+
+fn parse_header(
+    input: &str,
+) -> Result<(reqwest::header::HeaderName, reqwest::header::HeaderValue), String> {
+    let (name, value) = input
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: Value\", got {:?}", input))?;
+    let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("{:?} is not a valid header name", name.trim()))?;
+    let value = reqwest::header::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("the value of {} is not a valid header value", name))?;
+    Ok((name, value))
+}
+
+impl HttpArgs {
+    fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// Resolves the health path from the flag, then `STORAGE_HEALTH_PATH`,
+    /// normalized to a single leading slash.
+    fn health_path(&self) -> String {
+        let path = self
+            .health_path
+            .clone()
+            .or_else(|| env_var("STORAGE_HEALTH_PATH"))
+            .filter(|path| !path.trim().is_empty())
+            .unwrap_or_else(|| "/health".to_string());
+        format!("/{}", path.trim().trim_start_matches('/'))
+    }
 
-    let config_path = Path::new("rice.config.js");
-    if config_path.exists() {
-        let overwrite = Confirm::with_theme(&theme)
-            .with_prompt("rice.config.js already exists. Overwrite?")
-            .default(false)
-            .interact()?;
+    fn health_url(&self, endpoint: &Endpoint) -> String {
+        endpoint.http_health_url(self.scheme, &self.health_path())
+    }
 
-        if overwrite {
-            fs::write(config_path, config_content)?;
-            println!("{} Created rice.config.js", CHECK);
+    /// Without following, a redirect is reported as the 3xx it is.
+    fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        if self.follow_redirects == Some(false) {
+            reqwest::redirect::Policy::none()
         } else {
-            println!("{} Skipped rice.config.js", CHECK);
+            reqwest::redirect::Policy::limited(self.max_redirects())
         }
-    } else {
-        fs::write(config_path, config_content)?;
-        println!("{} Created rice.config.js", CHECK);
     }
 
-    // 3. Update .env
-    let env_content = format!(
-        "\n# Rice Configuration\nSTORAGE_INSTANCE_URL={}\nSTORAGE_USER={}\nSTORAGE_AUTH_TOKEN={}\nSTORAGE_HTTP_PORT={}\nSTATE_INSTANCE_URL={}\nSTATE_AUTH_TOKEN={}\nSTATE_RUN_ID={}\n",
-        storage_url,
-        storage_user,
-        storage_token,
-        storage_http_port,
-        state_url,
-        state_token,
-        state_run_id
-    );
+    fn max_redirects(&self) -> usize {
+        self.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS)
+    }
 
-    let env_path = Path::new(".env");
-    if env_path.exists() {
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(env_path)?;
-        write!(file, "{}", env_content)?;
-        println!("{} Appended to .env", CHECK);
-    } else {
-        fs::write(env_path, env_content)?;
-        println!("{} Created .env", CHECK);
+    fn client_builder(&self) -> reqwest::Result<reqwest::ClientBuilder> {
+        let timeout = self.timeout();
+        let user_agent = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| concat!("rice-cli/", env!("CARGO_PKG_VERSION")).to_string());
+        let mut builder = Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .user_agent(user_agent)
+            .redirect(self.redirect_policy());
+        if self.no_proxy {
+            builder = builder.no_proxy();
+        } else if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        if !self.headers.is_empty() {
+            let headers = self.headers.iter().cloned().collect();
+            builder = builder.default_headers(headers);
+        }
+        if self.insecure {
+            static WARNED: std::sync::Once = std::sync::Once::new();
+            WARNED.call_once(|| {
+                eprintln!(
+                    "{} {}",
+                    WARN,
+                    style("TLS certificate verification is disabled (--insecure)")
+                        .yellow()
+                        .bold()
+                )
+            });
+            builder = builder.tls_danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
     }
 
-    // 4. Verify Connection
-    if enable_storage {
-        println!(""); // Add a newline for spacing
-        let spinner = ProgressBar::new_spinner();
-        spinner.set_style(
-            ProgressStyle::default_spinner()
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ")
-                .template("{spinner:.green} {msg}")
-                .unwrap(),
-        );
-        spinner.set_message("Verifying connection to Storage...");
-        spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    fn client(&self) -> reqwest::Result<Client> {
+        self.client_builder()?.build()
+    }
+
+    /// A client for `endpoint`, connecting through its Unix socket when it
+    /// names one.
+    fn client_for(&self, endpoint: &Endpoint) -> Result<Client, RiceError> {
+        let Some(socket) = &endpoint.socket else {
+            return Ok(self.client()?);
+        };
+        #[cfg(unix)]
+        {
+            Ok(self.client_builder()?.unix_socket(socket.clone()).build()?)
+        }
+        #[cfg(not(unix))]
+        {
+            Err(FailureKind::Config.error(format!(
+                "unix:{} is a Unix socket, which this platform does not support; use a host:port or http(s):// URL",
+                socket.display()
+            )))
+        }
+    }
+
+    /// A client that speaks HTTP/2 from the first byte, as gRPC requires.
+    fn grpc_client(&self) -> reqwest::Result<Client> {
+        self.client_builder()?.http2_prior_knowledge().build()
+    }
+
+    /// Runs [`check_health`], describing a request error in the report.
+    async fn check_health(
+        &self,
+        client: &Client,
+        service: &'static str,
+        url: &str,
+        token: &str,
+        criteria: &HealthCriteria,
+    ) -> HealthReport {
+        check_health(client, service, url, token, criteria)
+            .await
+            .unwrap_or_else(|e| HealthReport::unreachable(service, url, self.describe(url, e)))
+    }
 
-        // Construct HTTP URL from storage_url host and storage_http_port
-        let host = if storage_url.contains(":") {
-            storage_url.split(':').next().unwrap_or("localhost")
+    /// Describes the proxy a request to `url` would go through, if any.
+    /// Environment proxies are reported as configured even if NO_PROXY
+    /// exempts the host.
+    fn proxy_for(&self, url: &str) -> Option<String> {
+        if self.no_proxy {
+            return None;
+        }
+        if let Some(proxy) = &self.proxy {
+            return Some(proxy.clone());
+        }
+        let vars: &[&str] = if url.starts_with("https://") {
+            &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
         } else {
-            &storage_url
+            &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]
         };
+        vars.iter()
+            .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+    }
 
-        let health_url = format!("http://{}:{}/health", host, storage_http_port);
+    /// Like [`HttpArgs::describe_error`] for a request error, else the
+    /// error's own message.
+    fn describe(&self, url: &str, error: RiceError) -> String {
+        match error {
+            RiceError::Network(e) => self.describe_error(url, &e),
+            e => e.to_string(),
+        }
+    }
 
-        let client = Client::new();
-        match client.get(&health_url).send().await {
-            Ok(res) => {
-                spinner.finish_and_clear();
-                if res.status().is_success() {
-                    println!(
-                        "{} Successfully connected to Rice Storage at {}",
-                        CHECK, health_url
-                    );
-                } else {
-                    println!("{} Connection failed: Status {}", CROSS, res.status());
-                    println!("   Please check if your Rice instance is running.");
-                }
-            }
-            Err(e) => {
-                spinner.finish_and_clear();
-                println!("{} Connection failed: {}", CROSS, e);
-                println!(
-                    "   Could not reach {}. Please ensure Rice is running and HTTP port is correct.",
-                    health_url
-                );
+    /// Explains a request error and what to check, noting the proxy when
+    /// one was in play.
+    fn describe_error(&self, url: &str, error: &reqwest::Error) -> String {
+        let target = error
+            .url()
+            .and_then(|url| {
+                let host = url.host_str()?;
+                Some(match url.port_or_known_default() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                })
+            })
+            .unwrap_or_else(|| url.to_string());
+        let cause = root_cause(error);
+        let message = match RequestFailure::classify(error) {
+            RequestFailure::Timeout => format!(
+                "timed out after {}s waiting for {}. Is the host reachable from here? \
+                 Raise --timeout if Rice is slow to answer.",
+                self.timeout().as_secs(),
+                target
+            ),
+            RequestFailure::Refused => format!(
+                "connection refused by {}. Is Rice running, and is the HTTP port correct?",
+                target
+            ),
+            RequestFailure::Dns => format!(
+                "could not resolve host {} ({}). Is the host in the instance URL spelled correctly?",
+                error.url().and_then(|url| url.host_str()).unwrap_or(url),
+                cause
+            ),
+            RequestFailure::Tls => format!(
+                "TLS handshake with {} failed ({}). Does the server speak HTTPS? \
+                 Try --scheme http if it does not, or --insecure for a self-signed certificate.",
+                target, cause
+            ),
+            RequestFailure::Redirects => format!(
+                "gave up after {} redirects starting at {}. Is a proxy redirecting in a loop? \
+                 Raise --max-redirects, or pass --follow-redirects=false to see the first response.",
+                self.max_redirects(),
+                url
+            ),
+            RequestFailure::Other => format!("{} ({})", error, cause),
+        };
+        match self.proxy_for(url) {
+            Some(proxy) => format!("{} (via proxy {})", message, proxy),
+            None => message,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum SecretStore {
+    /// Plaintext in .env
+    #[default]
+    Env,
+    /// The OS keychain; .env gets empty token values
+    Keyring,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    let global = &cli.global;
+    set_verbosity(global.verbosity());
+    SPINNERS
+        .set(
+            !global.no_spinner
+                && console::Term::stdout().is_term()
+                && console::Term::stderr().is_term(),
+        )
+        .ok();
+    if let Some(dir) = &global.cwd
+        && let Err(e) = std::env::set_current_dir(dir)
+    {
+        let message = format!("Could not change to {}: {}", dir.display(), e);
+        eprintln!("{} {}", CROSS, style(&message).red());
+        std::process::exit(RiceError::Config(message).exit_code());
+    }
+    let result = match cli.command {
+        Some(Commands::Setup(args)) => run_setup(*args, global).await,
+        None => run_setup(SetupArgs::default(), global).await,
+        Some(Commands::Config(args)) => run_config(args, global).await,
+        Some(Commands::Profiles) => run_profiles(global),
+        Some(Commands::Check(args)) => run_check(args, global).await,
+        Some(Commands::Ping(args)) => {
+            // Ping implies --watch, so the flags that conflict with it are
+            // rejected here, as clap does for `check --watch`.
+            let conflicts = [
+                ("--retries", args.retries != 0),
+                ("--wait", args.wait.is_some()),
+                ("--silent", args.silent),
+                ("--summary-only", args.summary_only),
+            ];
+            if let Some((flag, _)) = conflicts.into_iter().find(|&(_, given)| given) {
+                let mut command = Cli::command();
+                command.build();
+                command
+                    .find_subcommand_mut("ping")
+                    .expect("ping is a subcommand")
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        format!(
+                            "{} cannot be used with `ping`, which watches until Ctrl-C",
+                            flag
+                        ),
+                    )
+                    .exit();
             }
+            run_check(
+                CheckArgs {
+                    watch: true,
+                    ..args
+                },
+                global,
+            )
+            .await
+        }
+        Some(Commands::Status { http }) => run_status(http, global).await,
+        Some(Commands::Validate) => run_validate(global),
+        Some(Commands::Doctor { summary_only, http }) => {
+            run_doctor(http, summary_only, global).await
+        }
+        Some(Commands::Version { http }) => run_version(http, global).await,
+        Some(Commands::Export {
+            path,
+            include_secrets,
+        }) => run_export(&path, include_secrets, global),
+        Some(Commands::Import { path }) => run_import(&path, global),
+        Some(Commands::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "rice-cli",
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+        Some(Commands::Reset(args)) => run_reset(*args, global).await,
+        Some(Commands::Uninstall { yes }) => run_uninstall(yes, global),
+    };
+
+    if let Err(e) = result {
+        // An empty message means the failure was already reported.
+        if !e.to_string().is_empty() {
+            eprintln!("{} {}", CROSS, style(&e).red());
         }
+        std::process::exit(e.exit_code());
     }
+}
+
+/// Answers a yes/no question from its flag, else by prompting.
+/// Non-interactive runs fall back to `default`.
+fn ask_confirm(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    prompt: &str,
+    flag: Option<bool>,
+    default: bool,
+) -> Result<bool, RiceError> {
+    match flag {
+        Some(value) => Ok(value),
+        None if args.non_interactive => Ok(default),
+        None => Ok(Confirm::with_theme(theme)
+            .with_prompt(prompt)
+            .default(default)
+            .interact()?),
+    }
+}
 
-    println!("\n{}", style("Setup complete!").bold().green());
-    println!("You can now install the SDK using: npm install rice-node-sdk");
+/// Answers a text question from its flag, else by prompting until `validate`
+/// accepts the input. Non-interactive runs fall back to `default`.
+fn ask_input(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    prompt: &str,
+    flag: &Option<String>,
+    default: &str,
+    validate: fn(&str) -> Result<(), RiceError>,
+) -> Result<String, RiceError> {
+    let value = match flag {
+        Some(value) => value.clone(),
+        None if args.non_interactive => default.to_string(),
+        None => {
+            return Ok(Input::with_theme(theme)
+                .with_prompt(prompt)
+                .default(default.into())
+                .validate_with(|input: &String| validate(input))
+                .interact_text()?);
+        }
+    };
+    validate(&value).map_err(|e| RiceError::Validation(format!("Invalid {}: {}", prompt, e)))?;
+    Ok(value)
+}
 
+fn any_value(_: &str) -> Result<(), RiceError> {
     Ok(())
 }
 
-fn run_config() -> Result<(), Box<dyn std::error::Error>> {
-    dotenvy::dotenv().ok();
-    println!("{}", style("Rice Configuration:").bold().green());
-
-    let vars = [
-        "STORAGE_INSTANCE_URL",
-        "STORAGE_USER",
-        "STORAGE_AUTH_TOKEN",
-        "STORAGE_HTTP_PORT",
-        "STATE_INSTANCE_URL",
-        "STATE_AUTH_TOKEN",
-        "STATE_RUN_ID",
-    ];
-
-    for var in vars {
-        if let Ok(val) = std::env::var(var) {
-            let display_val = if var.contains("TOKEN") {
-                "********"
+/// Answers a secret from its flag, else by prompting without echo. An empty
+/// answer, or a non-interactive run, keeps the `current` secret.
+fn ask_password(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    prompt: &str,
+    flag: &Option<String>,
+    current: &str,
+) -> Result<String, RiceError> {
+    match flag {
+        Some(value) => Ok(value.clone()),
+        None if args.non_interactive => Ok(current.to_string()),
+        None => {
+            let prompt = if current.is_empty() {
+                prompt.to_string()
             } else {
-                &val
+                format!("{} (leave empty to keep current)", prompt)
             };
-            println!("{}: {}", var, display_val);
-        } else {
-            println!("{}: {}", var, style("Not set").dim());
+            let value = Password::with_theme(theme)
+                .with_prompt(prompt)
+                .allow_empty_password(true)
+                .interact()?;
+            Ok(if value.is_empty() {
+                current.to_string()
+            } else {
+                value
+            })
         }
     }
+}
 
-    if Path::new("rice.config.js").exists() {
-        println!("\nrice.config.js found.");
-    } else {
-        println!("\nrice.config.js not found.");
+/// Non-interactive runs can't ask for a missing token, so an enabled service
+/// without one fails instead of being set up with an empty token. An empty
+/// flag value, such as `--storage-token ""`, still opts out of auth, and so
+/// does a template that sets an empty token.
+fn require_token(
+    args: &SetupArgs,
+    service: &str,
+    token_flag: &Option<String>,
+    token: &str,
+) -> Result<(), RiceError> {
+    let var = format!("{}_AUTH_TOKEN", service.to_uppercase());
+    let from_template = args
+        .template
+        .and_then(|template| template.default_value(&var))
+        .is_some();
+    if !args.non_interactive || token_flag.is_some() || from_template || !token.is_empty() {
+        return Ok(());
     }
-
-    Ok(())
+    let flag = format!("{}-token", service.to_lowercase());
+    Err(RiceError::Validation(format!(
+        "{} is enabled but has no auth token; pass --{}, --{}-file or --{}-stdin (or --{} \"\" for none).",
+        service, flag, flag, flag, flag
+    )))
 }
 
-async fn run_check() -> Result<(), Box<dyn std::error::Error>> {
-    dotenvy::dotenv().ok();
-    println!("{}", style("Checking connection to Rice...").bold());
-
-    let storage_url =
-        std::env::var("STORAGE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
-    let http_port = std::env::var("STORAGE_HTTP_PORT").unwrap_or("3000".to_string());
-
-    let host = if storage_url.contains(":") {
-        storage_url.split(':').next().unwrap_or("localhost")
+/// Resolves a token given as a flag value, a file, or stdin. Files and stdin
+/// keep the secret out of shell history; one trailing newline is dropped.
+fn read_token(
+    flag: &Option<String>,
+    file: &Option<PathBuf>,
+    stdin: bool,
+) -> Result<Option<String>, RiceError> {
+    let content = if let Some(path) = file {
+        fs::read_to_string(path)
+            .map_err(|e| RiceError::Config(format!("Could not read {}: {}", path.display(), e)))?
+    } else if stdin {
+        std::io::read_to_string(std::io::stdin())?
     } else {
-        &storage_url
+        return Ok(flag.clone());
     };
+    let token = content
+        .strip_suffix('\n')
+        .map(|token| token.strip_suffix('\r').unwrap_or(token))
+        .unwrap_or(&content);
+    Ok(Some(token.to_string()))
+}
 
-    let health_url = format!("http://{}:{}/health", host, http_port);
-
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::default_spinner()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ")
-            .template("{spinner:.green} {msg}")
-            .unwrap(),
+/// Catches an HTTP port that repeats the gRPC port of a bare `host:port`
+/// instance URL, which can't serve both. Warns when prompting and fails
+/// non-interactive runs.
+fn check_port_clash(
+    args: &SetupArgs,
+    service: &str,
+    instance_url: &str,
+    http_port: &str,
+) -> Result<(), RiceError> {
+    let Ok(endpoint) = Endpoint::new(instance_url, http_port) else {
+        return Ok(());
+    };
+    if endpoint.scheme.is_some() || endpoint.http_port.is_none() {
+        return Ok(());
+    }
+    if endpoint.http_port != endpoint.grpc_port.or(Some(50051)) {
+        return Ok(());
+    }
+    let message = format!(
+        "{} HTTP port {} is the same as the gRPC port in {}; the HTTP health endpoint usually listens on a separate port such as 3000.",
+        service, http_port, instance_url
     );
-    spinner.set_message(format!("Checking Storage health at {}...", health_url));
-    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    if args.non_interactive {
+        return Err(RiceError::Validation(message));
+    }
+    println!("{} {}", WARN, message);
+    Ok(())
+}
 
-    let client = Client::new();
-    match client.get(&health_url).send().await {
-        Ok(res) => {
-            spinner.finish_and_clear();
-            if res.status().is_success() {
-                println!("{} Storage is healthy (Status: {})", CHECK, res.status());
-            } else {
-                println!("{} Storage is unhealthy (Status: {})", CROSS, res.status());
-            }
+/// `setup --edit`: opens the config file in `$VISUAL`, else `$EDITOR`, else
+/// the platform's default editor, then reports problems in the result. A
+/// missing or failing editor only warns, since the file is already written.
+fn edit_config(config_path: &Path, format: ConfigFormat) {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .find_map(|var| {
+            std::env::var(var)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        })
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    // The variables may hold arguments too, as in `code --wait`.
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or_default();
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(config_path)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            println!("{} {} exited with {}", WARN, editor, status);
+            return;
         }
         Err(e) => {
-            spinner.finish_and_clear();
-            println!("{} Failed to connect to Storage: {}", CROSS, e);
+            println!(
+                "{} Could not start {} ({}). Set $EDITOR, or open {} yourself.",
+                WARN,
+                editor,
+                e,
+                config_path.display()
+            );
+            return;
+        }
+    }
+
+    match validate_config_path(config_path, format).unwrap_or_else(|e| Err(vec![e.to_string()])) {
+        Ok(_) => println!("{} Edited {}", CHECK, config_path.display()),
+        Err(problems) => {
+            println!(
+                "{} {} has problems after editing:",
+                WARN,
+                config_path.display()
+            );
+            for problem in problems {
+                println!("   - {}", problem);
+            }
         }
     }
+}
+
+/// Inside a container, `localhost` is the container itself rather than the
+/// host that may be running Rice. Warns about a loopback instance URL and
+/// offers `host.docker.internal` instead; non-interactive runs keep the URL.
+fn check_container_host(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    service: &str,
+    instance_url: String,
+) -> Result<String, RiceError> {
+    let Some(docker_url) = docker_host_url(&instance_url) else {
+        return Ok(instance_url);
+    };
+    println!(
+        "{} Setup is running in a container, so {} reaches the container itself, not the host. If {} runs on the host, use {}.",
+        WARN, instance_url, service, docker_url
+    );
+    let prompt = format!("Use {} instead?", docker_url);
+    if ask_confirm(
+        theme,
+        args,
+        &prompt,
+        args.non_interactive.then_some(false),
+        true,
+    )? {
+        Ok(docker_url)
+    } else {
+        Ok(instance_url)
+    }
+}
 
+fn warn_http_port(service: &str, instance_url: &str, http_port: String) -> String {
+    let (port, warning) = reconcile_http_port(instance_url, &http_port);
+    if let Some(warning) = warning {
+        println!("{} {} {}", WARN, service, warning);
+    }
+    port
+}
+
+/// Verifies State at `target`, over HTTP with the `--timeout` and
+/// redirect settings of `http`.
+async fn probe_state_target(
+    target: &StateTarget,
+    http: &HttpArgs,
+    client: &Client,
+    token: &str,
+    criteria: &HealthCriteria,
+) -> HealthReport {
+    match target {
+        StateTarget::Grpc(addr) => check_reachable("state", addr, http.timeout()).await,
+        StateTarget::Http(url) => {
+            http.check_health(client, "state", url, token, criteria)
+                .await
+        }
+    }
+}
+
+fn new_spinner(message: impl Into<std::borrow::Cow<'static, str>>) -> ProgressBar {
+    if verbosity() == Verbosity::Quiet {
+        return ProgressBar::hidden();
+    }
+    start_spinner(ProgressBar::new_spinner(), message)
+}
+
+/// Holds the spinners of probes that run concurrently, one line each.
+/// Print through [`MultiProgress::suspend`] while it is active.
+fn spinner_group() -> MultiProgress {
+    if verbosity() == Verbosity::Quiet {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+fn add_spinner(
+    group: &MultiProgress,
+    message: impl Into<std::borrow::Cow<'static, str>>,
+) -> ProgressBar {
+    start_spinner(group.add(ProgressBar::new_spinner()), message)
+}
+
+/// Without spinners, prints the message once as a plain line instead.
+fn start_spinner(
+    spinner: ProgressBar,
+    message: impl Into<std::borrow::Cow<'static, str>>,
+) -> ProgressBar {
+    if !spinners() {
+        say!("{}", message.into());
+        return ProgressBar::hidden();
+    }
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ")
+            .template("{spinner:.green} {msg}")
+            .unwrap(),
+    );
+    spinner.set_message(message);
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    spinner
+}
+
+async fn run_setup(args: SetupArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    say!("{}", style("Welcome to the Rice CLI Setup").bold().green());
+    say!("This utility will walk you through setting up Rice in your project.\n");
+
+    let theme = ColorfulTheme::default();
+
+    // Values from --template, else from a previous setup, else from
+    // .ricerc, become the prompt defaults.
+    global.load_env();
+    let ricerc = ricerc_defaults()?;
+    let template = |var: &str| {
+        args.template
+            .and_then(|template| template.default_value(var))
+    };
+    let existing = |var: &str, fallback: &str| {
+        template(var)
+            .map(str::to_string)
+            .or_else(|| env_var(var).filter(|value| !value.is_empty()))
+            .or_else(|| {
+                ricerc
+                    .iter()
+                    .find(|(key, _)| *key == var)
+                    .map(|(_, value)| value.clone())
+            })
+            .unwrap_or_else(|| fallback.to_string())
+    };
+    let previously_enabled = |var: &str| parse_env_bool(&existing(var, "true")).unwrap_or(true);
+    let template_enabled = |var: &str| template(var).and_then(parse_env_bool);
+
+    if args.non_interactive
+        && args.enable_storage.is_none()
+        && args.enable_state.is_none()
+        && args.template.is_none()
+    {
+        return Err(RiceError::Validation(
+            "Non-interactive setup requires --enable-storage and/or --enable-state (or --template)."
+                .into(),
+        ));
+    }
+    if args.non_interactive
+        && args.enable_storage.or(template_enabled("STORAGE_ENABLED")) != Some(true)
+        && args.enable_state.or(template_enabled("STATE_ENABLED")) != Some(true)
+    {
+        return Err(RiceError::Validation(
+            "Both services are disabled; pass --enable-storage or --enable-state.".into(),
+        ));
+    }
+
+    let storage_token_flag = read_token(
+        &args.storage_token,
+        &args.storage_token_file,
+        args.storage_token_stdin,
+    )?;
+    let state_token_flag = read_token(
+        &args.state_token,
+        &args.state_token_file,
+        args.state_token_stdin,
+    )?;
+
+    // 1. Configuration Questions
+    let enable_storage = ask_confirm(
+        &theme,
+        &args,
+        "Enable Rice Storage?",
+        args.enable_storage,
+        // Services must be opted into explicitly (or by a template) when
+        // not prompting.
+        (!args.non_interactive || args.template.is_some()) && previously_enabled("STORAGE_ENABLED"),
+    )?;
+
+    let enable_state = ask_confirm(
+        &theme,
+        &args,
+        "Enable Rice State (AI Agent Memory)?",
+        args.enable_state,
+        (!args.non_interactive || args.template.is_some()) && previously_enabled("STATE_ENABLED"),
+    )?;
+
+    if !enable_storage && !enable_state {
+        return Err(FailureKind::Config.error("You must enable at least one service."));
+    }
+
+    // Storage Config
+    // Loopback URLs would point into the container, not at the host.
+    let container = in_container();
+    if container {
+        debug!("Running in a container");
+    }
+    let mut storage_url = existing("STORAGE_INSTANCE_URL", "localhost:50051");
+    let mut storage_user = existing("STORAGE_USER", "admin");
+    let mut storage_token = global.secret("STORAGE_AUTH_TOKEN");
+    let mut storage_http_port = existing("STORAGE_HTTP_PORT", "3000");
+
+    if enable_storage {
+        say!("\n{}", style("Storage Configuration").bold());
+
+        if container && !args.non_interactive {
+            storage_url = docker_host_url(&storage_url).unwrap_or(storage_url);
+        }
+        storage_url = ask_input(
+            &theme,
+            &args,
+            "Storage Instance URL",
+            &args.storage_url,
+            &storage_url,
+            validate_instance_url,
+        )?;
+        if container {
+            storage_url = check_container_host(&theme, &args, "Storage", storage_url)?;
+        }
+
+        storage_user = ask_input(
+            &theme,
+            &args,
+            "Storage User",
+            &args.storage_user,
+            &storage_user,
+            any_value,
+        )?;
+
+        storage_token = ask_password(
+            &theme,
+            &args,
+            "Storage Auth Token/Password",
+            &storage_token_flag,
+            &storage_token,
+        )?;
+        require_token(&args, "Storage", &storage_token_flag, &storage_token)?;
+
+        if let Some(port) = url_http_port(&storage_url) {
+            storage_http_port = port.to_string();
+        }
+        storage_http_port = ask_input(
+            &theme,
+            &args,
+            "Storage HTTP Port (for verification)",
+            &args.storage_http_port,
+            &storage_http_port,
+            validate_http_port,
+        )?;
+        storage_http_port = warn_http_port("Storage", &storage_url, storage_http_port);
+        check_port_clash(&args, "Storage", &storage_url, &storage_http_port)?;
+    }
+
+    // State Config
+    let mut state_url = existing("STATE_INSTANCE_URL", "localhost:50051");
+    let mut state_token = global.secret("STATE_AUTH_TOKEN");
+    let mut state_run_id = existing("STATE_RUN_ID", "default");
+    let mut state_http_port = existing("STATE_HTTP_PORT", "");
+
+    if enable_state {
+        say!("\n{}", style("State Configuration").bold());
+
+        if container && !args.non_interactive {
+            state_url = docker_host_url(&state_url).unwrap_or(state_url);
+        }
+        state_url = ask_input(
+            &theme,
+            &args,
+            "State Instance URL",
+            &args.state_url,
+            &state_url,
+            validate_state_url,
+        )?;
+        if container {
+            state_url = check_container_host(&theme, &args, "State", state_url)?;
+        }
+
+        state_token = ask_password(
+            &theme,
+            &args,
+            "State Auth Token",
+            &state_token_flag,
+            &state_token,
+        )?;
+        require_token(&args, "State", &state_token_flag, &state_token)?;
+
+        state_run_id = ask_input(
+            &theme,
+            &args,
+            "State Run ID",
+            &args.state_run_id,
+            &state_run_id,
+            validate_run_id,
+        )?
+        .trim()
+        .to_string();
+
+        state_http_port = ask_input(
+            &theme,
+            &args,
+            "State HTTP Port (for verification, empty to check the gRPC port)",
+            &args.state_http_port,
+            &state_http_port,
+            validate_optional_http_port,
+        )?;
+        state_http_port = warn_http_port("State", &state_url, state_http_port);
+        check_port_clash(&args, "State", &state_url, &state_http_port)?;
+    }
+
+    // Review the answers before anything is written.
+    let masked = |token: &str| {
+        if token.is_empty() {
+            style("(none)").dim().to_string()
+        } else {
+            mask_secret(token)
+        }
+    };
+    let mut summary = Vec::new();
+    if enable_storage {
+        summary.extend([
+            ("Storage Instance URL", storage_url.clone()),
+            ("Storage User", storage_user.clone()),
+            ("Storage Auth Token", masked(&storage_token)),
+            ("Storage HTTP Port", storage_http_port.clone()),
+        ]);
+    } else {
+        summary.push(("Storage", style("disabled").dim().to_string()));
+    }
+    if enable_state {
+        summary.extend([
+            ("State Instance URL", state_url.clone()),
+            ("State Auth Token", masked(&state_token)),
+            ("State Run ID", state_run_id.clone()),
+            (
+                "State HTTP Port",
+                if state_http_port.is_empty() {
+                    style("(none)").dim().to_string()
+                } else {
+                    state_http_port.clone()
+                },
+            ),
+        ]);
+    } else {
+        summary.push(("State", style("disabled").dim().to_string()));
+    }
+    say!("\n{}", style("Summary").bold());
+    for (label, value) in &summary {
+        say!("   {:<22}{}", format!("{}:", label), value);
+    }
+    let apply = ask_confirm(&theme, &args, "Apply these settings?", None, true)?;
+    if !apply {
+        println!("Setup cancelled. Nothing was written.");
+        return Ok(());
+    }
+
+    let config_name = global
+        .config_file
+        .clone()
+        .or(args.config_name.as_ref().map(PathBuf::from));
+    let existing = global.locate_config();
+    let config_format = args
+        .config_format
+        .or(args.config_dir.as_ref().map(|_| ConfigFormat::Js))
+        .or(config_name.as_deref().map(ConfigFormat::from_path))
+        .or(existing.as_ref().map(|(_, format)| *format))
+        .unwrap_or_default();
+    // An existing variant such as rice.config.cjs is rewritten in place.
+    let config_path = config_name
+        .or(args.config_dir.clone())
+        .or(existing
+            .filter(|(_, format)| *format == config_format)
+            .map(|(path, _)| path))
+        .unwrap_or_else(|| PathBuf::from(config_format.file_name()));
+    let setup = RiceSetup {
+        fragments: args.config_dir.is_some() || config_path.is_dir(),
+        config_path,
+        config_format,
+        module_format: args.module_format,
+        env_path: if args.local {
+            PathBuf::from(LOCAL_ENV_FILE)
+        } else {
+            global.env_path()
+        },
+        env_mode: args.env_mode,
+        env_prefix: global.env_prefix(),
+        persist_tokens: !args.no_persist_token && args.secret_store == SecretStore::Env,
+        update_example: !args.no_example,
+        ..RiceSetup::new(RiceConfig {
+            storage: StorageConfig {
+                enabled: enable_storage,
+                instance_url: storage_url,
+                user: storage_user,
+                auth_token: storage_token,
+                http_port: storage_http_port,
+                health_path: args.http.health_path(),
+            },
+            state: StateConfig {
+                enabled: enable_state,
+                instance_url: state_url,
+                auth_token: state_token,
+                run_id: state_run_id,
+                http_port: state_http_port,
+            },
+        })
+    };
+    let (storage, state) = (&setup.config.storage, &setup.config.state);
+    let mut env_values = setup.env_file_values();
+    // Fail before the keyring or the config file is written rather than
+    // halfway through.
+    if setup.writes_env() && !args.dry_run {
+        ensure_env_writable(&setup.env_path)?;
+    }
+    // Store tokens before the files, so a failing keyring leaves them untouched.
+    if !args.no_persist_token {
+        store_secrets(
+            global,
+            &args,
+            &[
+                ("STORAGE_AUTH_TOKEN", &storage.auth_token),
+                ("STATE_AUTH_TOKEN", &state.auth_token),
+            ],
+        )?;
+    }
+
+    // 2. Generate the config file
+    say!("\n{}", style("Generating configuration files...").bold());
+
+    let config_path = &setup.config_path;
+    if setup.fragments && !args.dry_run {
+        fs::create_dir_all(config_path)?;
+    }
+    let mut config_written = false;
+    for (path, content) in setup.config_files() {
+        config_written |= write_config_file(&theme, &args, &path, &content, setup.config_format)?;
+    }
+
+    // 3. Update .env, which only holds the auth tokens next to a TOML config file
+    let env_path = &setup.env_path;
+    if setup.config_format == ConfigFormat::Toml && config_written {
+        if setup.writes_env() {
+            say!(
+                "   Connection settings go in {}; the auth tokens go in {}.",
+                config_path.display(),
+                env_path.display()
+            );
+        } else {
+            say!(
+                "   Connection settings go in {}; {} is left unchanged.",
+                config_path.display(),
+                env_path.display()
+            );
+        }
+    }
+    if setup.writes_env() {
+        let existing_env = if env_path.exists() {
+            fs::read_to_string(env_path)?
+        } else {
+            String::new()
+        };
+        // A Rice key defined outside the Rice block may belong to something else.
+        let foreign = if args.env_mode == EnvMode::Merge {
+            foreign_env_values(&existing_env, &setup.env_prefix)
+        } else {
+            Vec::new()
+        };
+        for (var, current) in &foreign {
+            let Some(entry) = env_values.iter_mut().find(|(key, _)| key == var) else {
+                continue;
+            };
+            if entry.1 == current {
+                continue;
+            }
+            let shown = |value: &str| {
+                if SECRET_ENV_KEYS.contains(&var.as_str()) {
+                    mask_secret(value)
+                } else {
+                    value.to_string()
+                }
+            };
+            let prompt = format!(
+                "{} already sets {}={} outside the Rice block. Overwrite it with {}?",
+                env_path.display(),
+                var,
+                shown(current),
+                shown(entry.1)
+            );
+            if !ask_confirm(&theme, &args, &prompt, args.force.then_some(true), true)? {
+                entry.1 = current;
+            } else if args.non_interactive {
+                println!(
+                    "{} Overwriting {} in {}, which was set outside the Rice block",
+                    WARN,
+                    var,
+                    env_path.display()
+                );
+            }
+        }
+        if args.env_mode == EnvMode::Overwrite
+            && existing_env
+                .lines()
+                .any(|line| !line.trim().is_empty() && !is_rice_env_line(line, &setup.env_prefix))
+        {
+            println!(
+                "{} --env-mode overwrite drops the non-Rice lines in {}",
+                WARN,
+                env_path.display()
+            );
+        }
+        apply_file(
+            env_path,
+            &setup
+                .env_mode
+                .apply(&existing_env, &setup.env_prefix, &env_values),
+            &args,
+        )
+        .map_err(|e| env_write_error(env_path, e))?;
+        if setup.writes_example() {
+            let example_path = setup.example_path();
+            let existing_example = if example_path.exists() {
+                fs::read_to_string(&example_path)?
+            } else {
+                String::new()
+            };
+            if let Some(example) = merge_env_example(&existing_example, &setup.env_prefix) {
+                apply_file(&example_path, &example, &args)?;
+            }
+        }
+    }
+    if args.no_persist_token {
+        say!(
+            "   Auth tokens were not saved. Provide STORAGE_AUTH_TOKEN and STATE_AUTH_TOKEN at runtime."
+        );
+    }
+    if setup.writes_env() {
+        ignore_env_file(&theme, &args, env_path)?;
+    }
+    if args.edit {
+        edit_config(config_path, setup.config_format);
+    }
+
+    // 4. Verify Connection
+    if args.dry_run {
+        say!(
+            "{} Skipped connection verification",
+            style("[dry-run]").yellow()
+        );
+    } else if args.no_verify {
+        say!("\n{}", style("Skipped connection verification").dim());
+    }
+
+    let verify_failure = if args.dry_run || args.no_verify {
+        None
+    } else {
+        say!(); // Add a newline for spacing
+        let group = spinner_group();
+        let storage = async {
+            if storage.enabled {
+                verify_storage(
+                    &args,
+                    &group,
+                    &storage.instance_url,
+                    &storage.http_port,
+                    &storage.auth_token,
+                )
+                .await
+            } else {
+                Ok(None)
+            }
+        };
+        let state = async {
+            if state.enabled {
+                verify_state(
+                    &args,
+                    &group,
+                    &state.instance_url,
+                    &state.http_port,
+                    &state.auth_token,
+                )
+                .await
+            } else {
+                Ok(None)
+            }
+        };
+        let (storage, state) = tokio::join!(storage, state);
+        storage?.or(state?)
+    };
+
+    if args.dry_run {
+        say!(
+            "\n{}",
+            style("Dry run complete. No files were changed.")
+                .bold()
+                .green()
+        );
+        return Ok(());
+    }
+
+    if let Some(kind) = verify_failure {
+        return Err(kind.error("Setup wrote the configuration, but connection verification failed"));
+    }
+
+    say!("\n{}", style("Setup complete!").bold().green());
+    match find_package_json(config_path) {
+        Some(package_json) => match PackageManager::detect(&package_json).as_slice() {
+            [manager] => say!(
+                "You can now install the SDK using: {}",
+                manager.install_command()
+            ),
+            _ => {
+                say!("You can now install the SDK with your package manager:");
+                for manager in PackageManager::ALL {
+                    say!("   {}", manager.install_command());
+                }
+            }
+        },
+        None => offer_npm_project(&theme, &args)?,
+    }
+    print_next_steps(&setup);
+
+    Ok(())
+}
+
+/// Writes one config file of setup. An existing file is only overwritten
+/// after confirmation, which lists any fields it has that `content` drops.
+/// Returns whether the file was written.
+fn write_config_file(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    config_path: &Path,
+    config_content: &str,
+    format: ConfigFormat,
+) -> Result<bool, RiceError> {
+    if !config_path.exists() {
+        apply_file(config_path, config_content, args)?;
+        return Ok(true);
+    }
+    let lost = fs::read_to_string(config_path)
+        .map(|existing| lost_config_keys(&existing, config_content, format))
+        .unwrap_or_default();
+    let prompt = if lost.is_empty() {
+        format!("{} already exists. Overwrite?", config_path.display())
+    } else {
+        format!(
+            "{} already exists and has fields setup does not write, which would be lost: {}. Overwrite?",
+            config_path.display(),
+            lost.join(", ")
+        )
+    };
+    if args.force && !lost.is_empty() {
+        println!(
+            "{} Overwriting {} drops {}",
+            WARN,
+            config_path.display(),
+            lost.join(", ")
+        );
+    }
+    let overwrite = ask_confirm(theme, args, &prompt, args.force.then_some(true), false)?;
+
+    if overwrite {
+        apply_file(config_path, config_content, args)?;
+    } else {
+        println!("{} Skipped {}", CHECK, config_path.display());
+    }
+    Ok(overwrite)
+}
+
+/// Prompt defaults from `.ricerc`. Tokens in it are ignored with a warning,
+/// since the file is meant to be shared across projects.
+fn ricerc_defaults() -> Result<Vec<(&'static str, String)>, RiceError> {
+    let Some(path) = find_ricerc() else {
+        return Ok(Vec::new());
+    };
+    let content = fs::read_to_string(&path)?;
+    let values = ricerc_values(&content)
+        .map_err(|e| RiceError::Parse(format!("Could not parse {}: {}", path.display(), e)))?;
+    debug!("Using setup defaults from {}", path.display());
+    let (secrets, values): (Vec<_>, Vec<_>) = values
+        .into_iter()
+        .partition(|(var, _)| SECRET_ENV_KEYS.contains(var));
+    for (var, _) in secrets {
+        println!(
+            "{} Ignoring the {} in {}; keep tokens out of .ricerc",
+            WARN,
+            var,
+            path.display()
+        );
+    }
+    Ok(values)
+}
+
+/// Closes setup with a short snippet for each enabled service, written for
+/// the project's module format.
+fn print_next_steps(setup: &RiceSetup) {
+    let (storage, state) = (&setup.config.storage, &setup.config.state);
+    if !storage.enabled && !state.enabled {
+        return;
+    }
+    let import = match setup
+        .module_format
+        .unwrap_or_else(|| ModuleFormat::detect(&setup.config_path))
+    {
+        ModuleFormat::Esm => "import { Rice } from 'rice-node-sdk';",
+        ModuleFormat::Cjs => "const { Rice } = require('rice-node-sdk');",
+    };
+
+    say!("\n{}", style("Next steps:").bold());
+    say!(
+        "   Connect with the settings from {}:",
+        setup.config_path.display()
+    );
+    say!("     {}", style(import).cyan());
+    say!("     {}", style("const rice = new Rice();").cyan());
+    say!("     {}", style("await rice.connect();").cyan());
+    if storage.enabled {
+        say!("   Store and query data with Storage:");
+        say!(
+            "     {}",
+            style("await rice.storage.insert('greeting', { text: 'Hello, Rice' });").cyan()
+        );
+    }
+    if state.enabled {
+        say!("   Start a State run with the configured run ID:");
+        say!(
+            "     {}",
+            style(format!(
+                "const run = await rice.state.createRun('{}');",
+                state.run_id
+            ))
+            .cyan()
+        );
+    }
+}
+
+/// Commands that turn an empty directory into a Node project with the SDK.
+const NPM_PROJECT_COMMANDS: [&[&str]; 2] = [&["init", "-y"], &["install", "rice-node-sdk"]];
+
+/// Without a package.json there is nowhere to install the SDK, so offer to
+/// create one with npm. Non-interactive runs only print the commands.
+fn offer_npm_project(theme: &ColorfulTheme, args: &SetupArgs) -> Result<(), RiceError> {
+    let commands = NPM_PROJECT_COMMANDS
+        .map(|command| format!("npm {}", command.join(" ")))
+        .join(" && ");
+    say!(
+        "{} No package.json found, so there is no Node project to install the SDK into.",
+        WARN
+    );
+    let run = ask_confirm(
+        theme,
+        args,
+        "Create a package.json and install rice-node-sdk now?",
+        // Never run npm unasked in CI.
+        args.non_interactive.then_some(false),
+        true,
+    )?;
+    if !run {
+        say!("Create the project and install the SDK with: {}", commands);
+        return Ok(());
+    }
+
+    for command in NPM_PROJECT_COMMANDS {
+        let status = std::process::Command::new("npm").args(command).status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                return Err(RiceError::Other(format!(
+                    "`npm {}` failed ({})",
+                    command.join(" "),
+                    status
+                )));
+            }
+            Err(e) => {
+                return Err(RiceError::Other(format!(
+                    "Could not run npm ({}). Install Node.js, then run: {}",
+                    e, commands
+                )));
+            }
+        }
+    }
+    println!("{} Installed rice-node-sdk", CHECK);
+    Ok(())
+}
+
+/// Setup's Storage connection check, run alongside [`verify_state`].
+/// Returns how the check failed, if it did.
+async fn verify_storage(
+    args: &SetupArgs,
+    group: &MultiProgress,
+    storage_url: &str,
+    http_port: &str,
+    token: &str,
+) -> Result<Option<FailureKind>, RiceError> {
+    let endpoint = Endpoint::new(storage_url, http_port)?;
+    let health_url = args.http.health_url(&endpoint);
+    let client = args.http.client_for(&endpoint)?;
+    warn_token(group, "Storage", "STORAGE_AUTH_TOKEN", token);
+
+    let criteria = HealthCriteria::default();
+    let message = "Verifying connection to Storage...";
+    let spinner = add_spinner(group, message);
+    let (report, timed_out) = poll(
+        Polling::new(0, args.wait, Backoff::default()),
+        &spinner,
+        message,
+        || {
+            args.http
+                .check_health(&client, "storage", &health_url, token, &criteria)
+        },
+        |report| report.healthy,
+    )
+    .await;
+    spinner.finish_and_clear();
+
+    Ok(group.suspend(|| {
+        if timed_out {
+            print_wait_timeout("Storage", args.wait);
+        }
+        print_verification(&report, "HTTP port")
+    }))
+}
+
+/// Heads-up before a probe whose token is empty or a placeholder, which an
+/// instance that requires auth rejects.
+fn warn_token(group: &MultiProgress, service: &str, var: &str, token: &str) {
+    let problem = if token.trim().is_empty() {
+        match env_reference_of(var).filter(|name| env_var(name).is_none()) {
+            Some(name) => format!("is empty because ${{{}}} is not set", name),
+            None => "is empty".to_string(),
+        }
+    } else if is_placeholder_token(token) {
+        "looks like a placeholder".to_string()
+    } else {
+        return;
+    };
+    group.suspend(|| {
+        say!(
+            "{} {} {}, so the health check fails if {} requires authentication.",
+            WARN,
+            var,
+            problem,
+            service
+        )
+    });
+}
+
+/// Prints a setup verification result with a hint on failure, naming the
+/// setting most likely to be wrong when the service could not be reached.
+fn print_verification(report: &HealthReport, setting: &str) -> Option<FailureKind> {
+    let failure = FailureKind::of(report);
+    println!(
+        "{} {}",
+        if failure.is_none() { CHECK } else { CROSS },
+        report
+    );
+    match failure {
+        Some(FailureKind::Unreachable) => say!(
+            "   Could not reach {}. Please ensure Rice is running and the {} is correct.",
+            report.url,
+            setting
+        ),
+        Some(_) => say!("   Please check if your Rice instance is running."),
+        None => {}
+    }
+    failure
+}
+
+/// Setup's State connection check, run alongside [`verify_storage`].
+async fn verify_state(
+    args: &SetupArgs,
+    group: &MultiProgress,
+    state_url: &str,
+    http_port: &str,
+    token: &str,
+) -> Result<Option<FailureKind>, RiceError> {
+    let target = StateTarget::new(
+        state_url,
+        http_port,
+        args.http.scheme,
+        &args.http.health_path(),
+    )?;
+    let client = args.http.client()?;
+    // The gRPC reachability check sends no token.
+    if let StateTarget::Http(_) = target {
+        warn_token(group, "State", "STATE_AUTH_TOKEN", token);
+    }
+
+    let criteria = HealthCriteria::default();
+    let message = "Verifying connection to State...";
+    let spinner = add_spinner(group, message);
+    let (report, timed_out) = poll(
+        Polling::new(0, args.wait, Backoff::default()),
+        &spinner,
+        message,
+        || probe_state_target(&target, &args.http, &client, token, &criteria),
+        |report| report.healthy,
+    )
+    .await;
+    spinner.finish_and_clear();
+
+    Ok(group.suspend(|| {
+        if timed_out {
+            print_wait_timeout("State", args.wait);
+        }
+        print_verification(&report, "State URL")
+    }))
+}
+
+/// Saves tokens in the OS keyring with `--secret-store keyring`. Otherwise
+/// the tokens go to .env, so stale keyring entries, which would take
+/// precedence over .env, are removed.
+fn store_secrets(
+    global: &GlobalArgs,
+    args: &SetupArgs,
+    secrets: &[(&str, &str)],
+) -> Result<(), RiceError> {
+    for &(var, value) in secrets {
+        let entry = global.keyring_entry(var)?;
+        let keyring = args.secret_store == SecretStore::Keyring && !value.is_empty();
+        if args.dry_run {
+            if keyring {
+                println!(
+                    "{} Would store {} in the OS keyring",
+                    style("[dry-run]").yellow(),
+                    var
+                );
+            }
+            continue;
+        }
+        if keyring {
+            entry.set_password(value).map_err(|e| {
+                RiceError::Other(format!("Could not store {} in the OS keyring: {}", var, e))
+            })?;
+            println!("{} Stored {} in the OS keyring", CHECK, var);
+        } else if entry.delete_credential().is_ok() {
+            println!("{} Removed {} from the OS keyring", CHECK, var);
+        }
+    }
+    Ok(())
+}
+
+async fn run_config(args: ConfigArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    if !args.set.is_empty() {
+        return set_env_values(&args.set, global);
+    }
+    // Anything set before the env file loads came from the process environment.
+    let prefix = global.env_prefix();
+    let from_environment: Vec<&str> = RICE_ENV_KEYS
+        .into_iter()
+        .filter(|var| std::env::var_os(format!("{}{}", prefix, var)).is_some())
+        .collect();
+    let from_config_file = global.load_env();
+    let from_local_env_file = global.local_env_vars();
+    let resolve = |var: &str| -> Option<(String, ValueSource)> {
+        if SECRET_ENV_KEYS.contains(&var)
+            && let Ok(value) = global
+                .keyring_entry(var)
+                .and_then(|entry| entry.get_password())
+        {
+            return Some((expand_env_reference(value), ValueSource::Keyring));
+        }
+        if let Some(value) = env_var(var) {
+            let source = if from_environment.contains(&var) {
+                ValueSource::Environment
+            } else if from_local_env_file.iter().any(|local| local == var) {
+                ValueSource::LocalEnvFile
+            } else if from_config_file.contains(&var) {
+                ValueSource::ConfigFile
+            } else {
+                ValueSource::EnvFile
+            };
+            return Some((value, source));
+        }
+        Some(default_env_value(var))
+            .filter(|value| !value.is_empty())
+            .map(|value| (value.to_string(), ValueSource::Default))
+    };
+    let display = |var: &str, value: String| {
+        if SECRET_ENV_KEYS.contains(&var) && !args.show_secrets {
+            mask_secret(&value)
+        } else {
+            value
+        }
+    };
+    let config_file = global
+        .locate_config()
+        .map(|(path, _)| path.display().to_string());
+
+    if let Some(format) = args.format {
+        // Masked secrets would be sourced as real values, so leave them out.
+        for var in RICE_ENV_KEYS {
+            if SECRET_ENV_KEYS.contains(&var) && !args.show_secrets {
+                continue;
+            }
+            let Some((value, _)) = resolve(var) else {
+                continue;
+            };
+            match format {
+                VarsFormat::Env => println!("{}={}", var, shell_quote(&value)),
+                VarsFormat::Export => println!("export {}={}", var, shell_quote(&value)),
+                VarsFormat::Dotenv => println!("{}={}", var, quote_env_value(&value)),
+            }
+        }
+        return Ok(());
+    }
+
+    if global.structured() {
+        let mut variables = BTreeMap::new();
+        let mut sources = BTreeMap::new();
+        for var in RICE_ENV_KEYS {
+            let resolved = resolve(var);
+            sources.insert(var, resolved.as_ref().map(|(_, source)| source.as_str()));
+            variables.insert(var, resolved.map(|(value, _)| display(var, value)));
+        }
+        let services = if args.validate_connectivity {
+            let (storage, state) = check_services(&CheckArgs::default(), global, true).await?;
+            Some(vec![storage, state])
+        } else {
+            None
+        };
+        let failure = services
+            .as_deref()
+            .and_then(|services| services_failure(&services[0], &services[1]));
+        global.print_report(&ConfigReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            variables,
+            sources,
+            references: RICE_ENV_KEYS
+                .into_iter()
+                .filter_map(|var| Some((var, env_reference_of(var)?)))
+                .collect(),
+            config_file_found: config_file.is_some(),
+            config_file,
+            services,
+        })?;
+        return connectivity_result(failure);
+    }
+
+    say!("{}", style("Rice Configuration:").bold().green());
+
+    let env_file = format!("from {}", global.env_path().display());
+    let config_source = format!("from {}", config_file.as_deref().unwrap_or("config file"));
+    let rows: Vec<(&str, Option<(String, String)>)> = RICE_ENV_KEYS
+        .into_iter()
+        .map(|var| {
+            let resolved = resolve(var).map(|(value, source)| {
+                let label = match source {
+                    ValueSource::Keyring => "from keyring",
+                    ValueSource::Environment => "from environment",
+                    ValueSource::EnvFile => env_file.as_str(),
+                    ValueSource::LocalEnvFile => "from .env.local",
+                    ValueSource::ConfigFile => config_source.as_str(),
+                    ValueSource::Default => "default",
+                };
+                // A keyring secret is not read through the env files.
+                let source = match env_reference_of(var) {
+                    Some(name) if source != ValueSource::Keyring => match env_var(&name) {
+                        Some(_) => format!("{} via ${{{}}}", label, name),
+                        None => format!("{}, ${{{}}} is not set", label, name),
+                    },
+                    _ => label.to_string(),
+                };
+                (display(var, value), source)
+            });
+            (var, resolved)
+        })
+        .collect();
+
+    if console::Term::stdout().is_term() {
+        // Pad before styling, since escape codes would throw off the widths.
+        let key_width = RICE_ENV_KEYS.iter().map(|var| var.len()).max().unwrap_or(0);
+        let value_width = rows
+            .iter()
+            .filter_map(|(_, resolved)| resolved.as_ref())
+            .map(|(value, _)| console::measure_text_width(value))
+            .max()
+            .unwrap_or(0);
+        for (var, resolved) in rows {
+            let key = format!("{:<width$}", var, width = key_width);
+            match resolved {
+                Some((value, source)) => {
+                    let value =
+                        console::pad_str(&value, value_width, console::Alignment::Left, None);
+                    let value = if SECRET_ENV_KEYS.contains(&var) && !args.show_secrets {
+                        style(value).yellow()
+                    } else {
+                        style(value).green()
+                    };
+                    println!(
+                        "{}  {}  {}",
+                        style(key).bold(),
+                        value,
+                        style(format!("({})", source)).dim()
+                    );
+                }
+                None => println!("{}  {}", style(key).bold(), style("Not set").dim()),
+            }
+        }
+    } else {
+        for (var, resolved) in rows {
+            match resolved {
+                Some((value, source)) => println!("{}: {} ({})", var, value, source),
+                None => println!("{}: Not set", var),
+            }
+        }
+    }
+
+    match config_file {
+        Some(name) => println!("\n{} found.", name),
+        None => println!("\n{}.", global.config_not_found()),
+    }
+
+    if !args.validate_connectivity {
+        return Ok(());
+    }
+    say!("\n{}", style("Connectivity:").bold().green());
+    let (storage, state) = check_services(&CheckArgs::default(), global, false).await?;
+    connectivity_result(services_failure(&storage, &state))
+}
+
+/// Maps the outcome of a health check to the error `check` exits with.
+fn connectivity_result(failure: Option<FailureKind>) -> Result<(), RiceError> {
+    match failure {
+        Some(FailureKind::Unreachable) => {
+            Err(FailureKind::Unreachable.error("Could not reach Rice"))
+        }
+        Some(kind) => Err(kind.error("Rice is unhealthy")),
+        None => Ok(()),
+    }
+}
+
+/// Updates single Rice variables in the env file without rerunning setup.
+/// A `KEY` without `=VALUE` is prompted for, without echo for tokens. Tokens
+/// that live in the OS keyring are updated there instead.
+fn set_env_values(assignments: &[String], global: &GlobalArgs) -> Result<(), RiceError> {
+    global.load_env();
+    let theme = ColorfulTheme::default();
+    let mut env_values: Vec<(&str, String)> = Vec::new();
+    for assignment in assignments {
+        let (key, value) = match assignment.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.to_string())),
+            None => (assignment.trim(), None),
+        };
+        let Some(var) = RICE_ENV_KEYS.into_iter().find(|&var| var == key) else {
+            return Err(RiceError::Validation(format!(
+                "Unknown Rice variable '{}'. Expected one of: {}",
+                key,
+                RICE_ENV_KEYS.join(", ")
+            )));
+        };
+        let value = match value {
+            Some(value) => value,
+            None if SECRET_ENV_KEYS.contains(&var) => Password::with_theme(&theme)
+                .with_prompt(var)
+                .allow_empty_password(true)
+                .interact()?,
+            None => {
+                let current = global.lookup(var).unwrap_or_default();
+                Input::with_theme(&theme)
+                    .with_prompt(var)
+                    .default(current)
+                    .allow_empty(true)
+                    .validate_with(|input: &String| validate_env_value(var, input))
+                    .interact_text()?
+            }
+        };
+        validate_env_value(var, &value)
+            .map_err(|e| RiceError::Validation(format!("Invalid {}: {}", var, e)))?;
+
+        if SECRET_ENV_KEYS.contains(&var) {
+            let entry = global.keyring_entry(var)?;
+            if entry.get_password().is_ok() {
+                entry.set_password(&value).map_err(|e| {
+                    RiceError::Other(format!("Could not store {} in the OS keyring: {}", var, e))
+                })?;
+                println!("{} Stored {} in the OS keyring", CHECK, var);
+                continue;
+            }
+        }
+        env_values.push((var, value));
+    }
+    if env_values.is_empty() {
+        return Ok(());
+    }
+
+    let env_path = global.env_path();
+    let existing_env = if env_path.exists() {
+        fs::read_to_string(&env_path)?
+    } else {
+        String::new()
+    };
+    let env_values: Vec<(&str, &str)> = env_values
+        .iter()
+        .map(|(var, value)| (*var, value.as_str()))
+        .collect();
+    write_file(
+        &env_path,
+        &merge_env(&existing_env, &global.env_prefix(), &env_values),
+        false,
+        true,
+    )?;
+    for (var, _) in env_values {
+        println!("{} Set {}", CHECK, var);
+    }
+    Ok(())
+}
+
+/// `profiles --output json` result.
+#[derive(Serialize)]
+struct ProfilesReport {
+    schema_version: u32,
+    profiles: Vec<ProfileReport>,
+}
+
+/// One env file in a [`ProfilesReport`].
+#[derive(Serialize)]
+struct ProfileReport {
+    /// `None` for the default `.env`
+    name: Option<String>,
+    env_file: String,
+    default: bool,
+    storage_enabled: bool,
+    storage_url: Option<String>,
+    state_enabled: bool,
+    state_url: Option<String>,
+}
+
+/// Env files that are not profiles despite matching `.env.<name>`.
+const NON_PROFILE_ENV_FILES: [&str; 2] = [".env.example", LOCAL_ENV_FILE];
+
+fn run_profiles(global: &GlobalArgs) -> Result<(), RiceError> {
+    let mut names: Vec<String> = fs::read_dir(".")?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|file| !NON_PROFILE_ENV_FILES.contains(&file.as_str()))
+        .filter_map(|file| {
+            file.strip_prefix(".env.")
+                .and_then(|name| parse_profile(name).ok())
+        })
+        .collect();
+    names.sort();
+
+    let default = Path::new(".env")
+        .exists()
+        .then_some(None)
+        .into_iter()
+        .chain(names.into_iter().map(Some));
+    let profiles: Vec<ProfileReport> = default
+        .map(|name| {
+            let env_file = match &name {
+                Some(name) => format!(".env.{}", name),
+                None => ".env".to_string(),
+            };
+            let values: Vec<(String, String)> = dotenvy::from_path_iter(&env_file)
+                .map(|iter| iter.filter_map(Result::ok).collect())
+                .unwrap_or_default();
+            let value = |var: &str| {
+                values
+                    .iter()
+                    .find(|(key, _)| key == var)
+                    .map(|(_, value)| value.clone())
+            };
+            let enabled = |var: &str, fallback: bool| {
+                value(var)
+                    .and_then(|value| parse_env_bool(&value))
+                    .unwrap_or(fallback)
+            };
+            ProfileReport {
+                default: name.is_none(),
+                name,
+                env_file,
+                storage_enabled: enabled("STORAGE_ENABLED", true),
+                storage_url: value("STORAGE_INSTANCE_URL"),
+                state_enabled: enabled("STATE_ENABLED", false),
+                state_url: value("STATE_INSTANCE_URL"),
+            }
+        })
+        .collect();
+
+    if global.structured() {
+        global.print_report(&ProfilesReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            profiles,
+        })?;
+        return Ok(());
+    }
+
+    if profiles.is_empty() {
+        println!("No profiles found. Run `rice-cli setup` or `rice-cli setup --profile <name>`.");
+        return Ok(());
+    }
+    say!("{}", style("Rice Profiles:").bold().green());
+    let width = profiles
+        .iter()
+        .map(|profile| profile.name.as_deref().unwrap_or("default").len())
+        .max()
+        .unwrap_or_default();
+    let file_width = profiles
+        .iter()
+        .map(|profile| profile.env_file.len() + 2)
+        .max()
+        .unwrap_or_default();
+    let service = |enabled: bool, url: &Option<String>| {
+        if enabled {
+            url.clone().unwrap_or_else(|| "localhost:50051".to_string())
+        } else {
+            style("disabled").dim().to_string()
+        }
+    };
+    for profile in &profiles {
+        let name = profile.name.as_deref().unwrap_or("default");
+        println!(
+            "{} {:<width$}  {}  Storage: {}  State: {}",
+            if profile.default { "*" } else { " " },
+            name,
+            style(format!(
+                "{:<file_width$}",
+                format!("({})", profile.env_file)
+            ))
+            .dim(),
+            service(profile.storage_enabled, &profile.storage_url),
+            service(profile.state_enabled, &profile.state_url),
+        );
+    }
+    Ok(())
+}
+
+/// Decides which services `check` probes: `STORAGE_ENABLED`/`STATE_ENABLED`
+/// when set, else the config file's flags, else Storage only.
+fn enabled_services(global: &GlobalArgs) -> (bool, bool) {
+    let config = read_enabled_flags(global);
+    let enabled = |var: &str, fallback: bool| {
+        env_var(var)
+            .and_then(|value| parse_env_bool(&value))
+            .unwrap_or(fallback)
+    };
+    (
+        enabled("STORAGE_ENABLED", config.is_none_or(|(storage, _)| storage)),
+        enabled("STATE_ENABLED", config.is_some_and(|(_, state)| state)),
+    )
+}
+
+/// Runs `probe` until `done` accepts its result or `polling` gives up,
+/// showing progress on the spinner. Returns the last result and whether a
+/// `--wait` limit elapsed.
+async fn poll<T, F>(
+    polling: Polling,
+    spinner: &ProgressBar,
+    message: &str,
+    mut probe: impl FnMut() -> F,
+    done: impl Fn(&T) -> bool,
+) -> (T, bool)
+where
+    F: std::future::Future<Output = T>,
+{
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        match polling {
+            Polling::Retries(retries, _) if retries > 0 => {
+                spinner.set_message(format!("{} (attempt {}/{})", message, attempt, retries + 1))
+            }
+            Polling::Wait(_) => spinner.set_message(format!(
+                "{} ({}s elapsed)",
+                message,
+                started.elapsed().as_secs()
+            )),
+            Polling::Retries(..) => {}
+        }
+
+        let result = probe().await;
+        if done(&result) {
+            return (result, false);
+        }
+
+        let delay = match polling {
+            Polling::Retries(retries, _) if attempt > retries => return (result, false),
+            Polling::Retries(retries, backoff) => {
+                let delay = backoff.delay(attempt);
+                spinner.set_message(format!(
+                    "{} (attempt {}/{} failed, retrying in {:.1}s)",
+                    message,
+                    attempt,
+                    retries + 1,
+                    delay.as_secs_f64()
+                ));
+                delay
+            }
+            Polling::Wait(limit) => match limit.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => {
+                    remaining.min(std::time::Duration::from_secs(1))
+                }
+                _ => return (result, true),
+            },
+        };
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Version of the `--output json` schemas below. Bumped when a field is
+/// renamed, removed or changes type; adding a field does not bump it.
+const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// `check --output json` result.
+#[derive(Serialize)]
+struct CheckReport {
+    schema_version: u32,
+    services: Vec<ServiceReport>,
+}
+
+/// Result for one service in a [`CheckReport`].
+#[derive(Default, Serialize)]
+struct ServiceReport {
+    service: &'static str,
+    enabled: bool,
+    /// HTTP health endpoint that was probed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// gRPC address that was probed, for State without `STATE_HTTP_PORT`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    /// `None` when the service is disabled
+    healthy: Option<bool>,
+    /// HTTP status, `None` when no HTTP response was received
+    status: Option<u16>,
+    /// Time until the response (or gRPC connect) of the last attempt,
+    /// `None` when the service did not answer
+    latency_ms: Option<u64>,
+    error: Option<String>,
+    /// Whether `--wait` gave up; only present with `--wait`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timed_out: Option<bool>,
+    /// Storage gRPC health; only present with `--grpc`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grpc: Option<GrpcReport>,
+}
+
+impl ServiceReport {
+    fn disabled(service: &'static str) -> Self {
+        ServiceReport {
+            service,
+            ..Default::default()
+        }
+    }
+
+    fn from_health(health: &HealthReport) -> Self {
+        ServiceReport {
+            service: health.service,
+            enabled: true,
+            url: Some(health.url.clone()),
+            healthy: Some(health.healthy),
+            status: health.status_code,
+            latency_ms: health.latency_ms,
+            error: health.error.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the service and, with `--grpc`, its gRPC health both passed.
+    fn is_ok(&self) -> bool {
+        self.failure().is_none() && self.grpc.as_ref().is_none_or(|grpc| grpc.healthy)
+    }
+
+    /// Classifies a failed report: no status at all means the service could
+    /// not be reached, while a status means it answered but is unhealthy.
+    fn failure(&self) -> Option<FailureKind> {
+        match (self.healthy, self.status) {
+            (Some(false), None) => Some(FailureKind::Unreachable),
+            (Some(false), Some(_)) => Some(FailureKind::Unhealthy),
+            _ => None,
+        }
+    }
+}
+
+/// gRPC health result in a [`ServiceReport`].
+#[derive(Serialize)]
+struct GrpcReport {
+    url: String,
+    healthy: bool,
+    /// Health service status such as `SERVING`, `None` when unreachable
+    status: Option<&'static str>,
+    error: Option<String>,
+}
+
+impl GrpcReport {
+    fn failure(&self) -> Option<FailureKind> {
+        match (self.healthy, self.status) {
+            (false, None) => Some(FailureKind::Unreachable),
+            (false, Some(_)) => Some(FailureKind::Unhealthy),
+            _ => None,
+        }
+    }
+}
+
+/// One line of the `check --log-file` history.
+#[derive(Serialize)]
+struct CheckLogEntry<'a> {
+    timestamp: String,
+    service: &'static str,
+    /// Health URL, or the gRPC address for State without `STATE_HTTP_PORT`
+    url: Option<&'a str>,
+    status: Option<u16>,
+    latency_ms: Option<u64>,
+    ok: bool,
+}
+
+/// Appends a line per enabled service to the check log. All lines go out
+/// in one write to a file opened for appending, so concurrent runs do not
+/// interleave within a line.
+fn append_check_log(path: &Path, reports: &[&ServiceReport]) -> std::io::Result<()> {
+    let timestamp = iso8601_utc(std::time::SystemTime::now());
+    let mut lines = String::new();
+    for report in reports.iter().filter(|report| report.enabled) {
+        let entry = CheckLogEntry {
+            timestamp: timestamp.clone(),
+            service: report.service,
+            url: report.url.as_deref().or(report.address.as_deref()),
+            status: report.status,
+            latency_ms: report.latency_ms,
+            ok: report.is_ok(),
+        };
+        lines += &serde_json::to_string(&entry)?;
+        lines.push('\n');
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+    use std::io::Write;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(lines.as_bytes())
+}
+
+/// `config --output json` result.
+#[derive(Serialize)]
+struct ConfigReport {
+    schema_version: u32,
+    /// Resolved value of every Rice variable, `None` when unset
+    variables: BTreeMap<&'static str, Option<String>>,
+    /// Where each value came from, `None` when unset
+    sources: BTreeMap<&'static str, Option<&'static str>>,
+    /// The variable each `${NAME}` value was resolved from
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    references: BTreeMap<&'static str, String>,
+    config_file_found: bool,
+    config_file: Option<String>,
+    /// Health of each service; only present with `--validate-connectivity`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<ServiceReport>>,
+}
+
+/// `version --output json` result.
+#[derive(Serialize)]
+struct VersionReport {
+    schema_version: u32,
+    cli_version: &'static str,
+    server_version: Option<String>,
+    min_server_version: &'static str,
+    compatible: Option<bool>,
+}
+
+async fn run_check(args: CheckArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    global.load_env();
+    if !global.env_path().exists() && RICE_ENV_KEYS.iter().all(|var| env_var(var).is_none()) {
+        return Err(FailureKind::Config.error(format!(
+            "No Rice configuration found ({} does not exist). Run `rice-cli setup` first.",
+            global.env_path().display()
+        )));
+    }
+    let (storage_enabled, state_enabled) = enabled_services(global);
+    match args.service {
+        ServiceSelection::All if !storage_enabled && !state_enabled => {
+            return Err(FailureKind::Config.error(
+                "Both Storage and State are disabled, so there is nothing to check. \
+                 Enable one with `rice-cli setup` or `rice-cli config --set STATE_ENABLED=true`.",
+            ));
+        }
+        ServiceSelection::Storage if !storage_enabled => {
+            return Err(FailureKind::Config.error(
+                "Storage is disabled. Enable it with `rice-cli config --set STORAGE_ENABLED=true`.",
+            ));
+        }
+        ServiceSelection::State if !state_enabled => {
+            return Err(FailureKind::Config.error(
+                "State is disabled. Enable it with `rice-cli config --set STATE_ENABLED=true`.",
+            ));
+        }
+        _ => {}
+    }
+    if args.watch {
+        return run_watch(&args, global).await;
+    }
+    let json = global.structured();
+    let silent = args.silent && !global.verbose;
+    let quiet = json || silent || args.summary_only;
+    if !quiet {
+        say!("{}", style("Checking connection to Rice...").bold());
+    }
+
+    let (storage, state) = check_services(&args, global, quiet).await?;
+
+    let timed_out = [&storage, &state]
+        .iter()
+        .any(|report| report.timed_out == Some(true));
+    let failure = services_failure(&storage, &state);
+    if let Some(path) = args.log_path() {
+        append_check_log(&path, &[&storage, &state]).map_err(|e| {
+            RiceError::Other(format!(
+                "Could not write check log {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    }
+    if args.summary_only {
+        let checked: Vec<_> = [&storage, &state]
+            .into_iter()
+            .filter(|report| report.enabled)
+            .collect();
+        let passed = checked.iter().filter(|report| report.is_ok()).count();
+        SummaryReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            command: "check",
+            ok: failure.is_none(),
+            status: failure.map_or("ok", FailureKind::as_str),
+            passed,
+            failed: checked.len() - passed,
+            warnings: 0,
+        }
+        .print(global)?;
+        return match failure {
+            Some(kind) => Err(kind.error("")),
+            None => Ok(()),
+        };
+    }
+    let report = CheckReport {
+        schema_version: JSON_SCHEMA_VERSION,
+        services: args.service.filter([storage, state]),
+    };
+    if silent {
+        return match failure {
+            Some(kind) => {
+                eprintln!("{}", serde_json::to_string(&report)?);
+                Err(kind.error(""))
+            }
+            None => Ok(()),
+        };
+    }
+    if json {
+        global.print_report(&report)?;
+    }
+    match failure {
+        Some(kind) if timed_out => Err(kind.error("Timed out waiting for Rice to become ready")),
+        _ => connectivity_result(failure),
+    }
+}
+
+/// The first failure among the service reports, if any.
+fn services_failure(storage: &ServiceReport, state: &ServiceReport) -> Option<FailureKind> {
+    storage
+        .failure()
+        .or(state.failure())
+        .or(storage.grpc.as_ref().and_then(GrpcReport::failure))
+}
+
+/// Probes both services concurrently, printing the results unless `json`.
+async fn check_services(
+    args: &CheckArgs,
+    global: &GlobalArgs,
+    json: bool,
+) -> Result<(ServiceReport, ServiceReport), RiceError> {
+    let (storage_enabled, state_enabled) = enabled_services(global);
+    let group = spinner_group();
+    let storage = async {
+        if !args.service.includes("storage") {
+            Ok(ServiceReport::disabled("storage"))
+        } else if storage_enabled {
+            check_storage(args, global, json, &group).await
+        } else {
+            Ok(skip_service("Storage", "storage", json))
+        }
+    };
+    let state = async {
+        if !args.service.includes("state") {
+            Ok(ServiceReport::disabled("state"))
+        } else if state_enabled {
+            check_state(args, global, json, &group).await
+        } else {
+            Ok(skip_service("State", "state", json))
+        }
+    };
+    let (storage, state) = tokio::join!(storage, state);
+    Ok((storage?, state?))
+}
+
+/// `check --watch`: probes every `--interval` seconds and prints one line
+/// per round until Ctrl-C, then the uptime of each enabled service.
+async fn run_watch(args: &CheckArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    let json = global.structured();
+    if !json {
+        say!(
+            "{}",
+            style(format!(
+                "Checking Rice every {}s, press Ctrl-C to stop...",
+                args.interval
+            ))
+            .bold()
+        );
+    }
+
+    let started = Instant::now();
+    let mut rounds = 0u32;
+    let mut up = [("Storage", None), ("State", None)];
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        // Without a spinner, each round's own line is progress enough.
+        let spinner = if json || !spinners() {
+            ProgressBar::hidden()
+        } else {
+            new_spinner("Checking Rice...")
+        };
+        // Probe without printing; each round gets a single summary line.
+        let result = tokio::select! {
+            _ = &mut ctrl_c => None,
+            result = check_services(args, global, true) => Some(result),
+        };
+        spinner.finish_and_clear();
+        let Some(result) = result else {
+            break;
+        };
+        let (storage, state) = result?;
+
+        rounds += 1;
+        for ((_, count), report) in up.iter_mut().zip([&storage, &state]) {
+            if report.enabled {
+                *count.get_or_insert(0) += u32::from(report.is_ok());
+            }
+        }
+        if let Some(path) = args.log_path() {
+            append_check_log(&path, &[&storage, &state]).map_err(|e| {
+                RiceError::Other(format!(
+                    "Could not write check log {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        }
+        if json {
+            global.print_report(&CheckReport {
+                schema_version: JSON_SCHEMA_VERSION,
+                services: args.service.filter([storage, state]),
+            })?;
+        } else {
+            let entries: Vec<String> = [("Storage", &storage), ("State", &state)]
+                .into_iter()
+                .filter(|(_, report)| report.enabled)
+                .map(|(name, report)| watch_entry(name, report))
+                .collect();
+            println!(
+                "{}  {}",
+                style(iso8601_utc(std::time::SystemTime::now())).dim(),
+                entries.join("  ")
+            );
+        }
+
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(std::time::Duration::from_secs(args.interval)) => {}
+        }
+    }
+
+    if !json && rounds > 0 {
+        println!("\n{} checks in {}s", rounds, started.elapsed().as_secs());
+        for (name, count) in up {
+            if let Some(count) = count {
+                println!(
+                    "   {:<8} up {}/{} ({:.1}%)",
+                    name,
+                    count,
+                    rounds,
+                    f64::from(count) * 100.0 / f64::from(rounds)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One service's part of a `check --watch` line, such as
+/// `✔ Storage 200 OK 12ms`.
+fn watch_entry(name: &str, report: &ServiceReport) -> String {
+    let mut details = Vec::new();
+    if let Some(status) = report.status {
+        details.push(
+            reqwest::StatusCode::from_u16(status)
+                .map_or_else(|_| status.to_string(), |status| status.to_string()),
+        );
+    } else if report.latency_ms.is_none() {
+        details.push("unreachable".to_string());
+    }
+    if let Some(latency) = report.latency_ms {
+        details.push(format!("{}ms", latency));
+    }
+    if report.grpc.as_ref().is_some_and(|grpc| !grpc.healthy) {
+        details.push("gRPC down".to_string());
+    }
+    let mark = if report.is_ok() { CHECK } else { CROSS };
+    format!("{}{} {}", mark, name, details.join(" "))
+}
+
+/// Reports a service that is disabled and therefore not probed.
+fn skip_service(name: &str, service: &'static str, json: bool) -> ServiceReport {
+    if !json {
+        say!("{}", style(format!("Skipping {} (disabled)", name)).dim());
+    }
+    ServiceReport::disabled(service)
+}
+
+fn check_spinner(json: bool, group: &MultiProgress, message: &str) -> ProgressBar {
+    if json {
+        ProgressBar::hidden()
+    } else {
+        add_spinner(group, message.to_string())
+    }
+}
+
+/// Prints the line explaining that `--wait` gave up.
+fn print_wait_timeout(name: &str, wait: Option<u64>) {
+    println!(
+        "{} Gave up waiting for {} after {}s",
+        CROSS,
+        name,
+        wait.unwrap_or_default()
+    );
+}
+
+/// Probes Storage's HTTP health endpoint (and gRPC health with `--grpc`),
+/// printing the results unless `json`. Returns the JSON report.
+async fn check_storage(
+    args: &CheckArgs,
+    global: &GlobalArgs,
+    json: bool,
+    group: &MultiProgress,
+) -> Result<ServiceReport, RiceError> {
+    let storage_url = env_var("STORAGE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
+    let http_port = env_var("STORAGE_HTTP_PORT").unwrap_or("3000".to_string());
+    let token = global.secret("STORAGE_AUTH_TOKEN");
+    validate_http_port(&http_port)
+        .map_err(|e| FailureKind::Config.error(format!("Invalid STORAGE_HTTP_PORT: {}", e)))?;
+
+    let endpoint = Endpoint::new(&storage_url, &http_port)
+        .map_err(|e| FailureKind::Config.error(format!("Invalid STORAGE_INSTANCE_URL: {}", e)))?;
+    let health_url = args.http.health_url(&endpoint);
+    if args.grpc && endpoint.socket.is_some() {
+        return Err(FailureKind::Config
+            .error("--grpc needs a TCP address; STORAGE_INSTANCE_URL is a Unix socket"));
+    }
+
+    if args.check_dns
+        && endpoint.socket.is_none()
+        && let Err(e) = resolve_preflight(args, json, group, &endpoint.host).await
+    {
+        if !json {
+            group.suspend(|| println!("{} Storage: {}", CROSS, e));
+        }
+        return Ok(ServiceReport {
+            timed_out: args.wait.map(|_| false),
+            ..ServiceReport::from_health(&HealthReport::unreachable("storage", &health_url, e))
+        });
+    }
+
+    if !json {
+        warn_token(group, "Storage", "STORAGE_AUTH_TOKEN", &token);
+    }
+    let message = format!("Checking Storage health at {}...", health_url);
+    let spinner = check_spinner(json, group, &message);
+    let client = args.http.client_for(&endpoint)?;
+    let criteria = args.health_criteria();
+    let (health, timed_out) = poll(
+        args.polling(),
+        &spinner,
+        &message,
+        || {
+            args.http
+                .check_health(&client, "storage", &health_url, &token, &criteria)
+        },
+        |report| report.healthy,
+    )
+    .await;
+    spinner.finish_and_clear();
+
+    let grpc = if args.grpc {
+        let url = endpoint.grpc_health_url(args.http.scheme);
+        let spinner = check_spinner(
+            json,
+            group,
+            &format!("Checking Storage gRPC health at {}...", url),
+        );
+        let result = probe_grpc(&args.http.grpc_client()?, &url, &token).await;
+        spinner.finish_and_clear();
+        Some((url, result))
+    } else {
+        None
+    };
+
+    let report = ServiceReport {
+        timed_out: args.wait.map(|_| timed_out),
+        grpc: grpc.as_ref().map(|(url, result)| GrpcReport {
+            url: url.clone(),
+            healthy: result.as_ref().is_ok_and(|status| status.is_reachable()),
+            status: result.as_ref().ok().map(|status| status.as_str()),
+            error: result.as_ref().err().map(ToString::to_string),
+        }),
+        ..ServiceReport::from_health(&health)
+    };
+    if json {
+        return Ok(report);
+    }
+
+    group.suspend(|| {
+        if timed_out {
+            print_wait_timeout("Storage", args.wait);
+        }
+        println!("{} {}", if health.healthy { CHECK } else { CROSS }, health);
+
+        match grpc {
+            Some((_, Ok(GrpcStatus::Unimplemented))) => println!(
+                "{} Storage gRPC port is reachable (health service not implemented)",
+                CHECK
+            ),
+            Some((_, Ok(status))) if status.is_reachable() => {
+                println!("{} Storage gRPC is healthy ({})", CHECK, status.as_str())
+            }
+            Some((_, Ok(status))) => {
+                println!("{} Storage gRPC is unhealthy ({})", CROSS, status.as_str())
+            }
+            Some((_, Err(e))) => println!("{} Failed to reach Storage gRPC: {}", CROSS, e),
+            None => {}
+        }
+    });
+    Ok(report)
+}
+
+/// Probes State's HTTP health endpoint when `STATE_HTTP_PORT` is set, else
+/// its gRPC port, printing the result unless `json`. Returns the JSON report.
+async fn check_state(
+    args: &CheckArgs,
+    global: &GlobalArgs,
+    json: bool,
+    group: &MultiProgress,
+) -> Result<ServiceReport, RiceError> {
+    let state_url = env_var("STATE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
+    let http_port = env_var("STATE_HTTP_PORT").unwrap_or_default();
+    let token = global.secret("STATE_AUTH_TOKEN");
+    let target = StateTarget::new(
+        &state_url,
+        &http_port,
+        args.http.scheme,
+        &args.http.health_path(),
+    )?;
+    let client = args.http.client()?;
+
+    if args.check_dns
+        && let Ok(endpoint) = Endpoint::new(&state_url, &http_port)
+        && let Err(e) = resolve_preflight(args, json, group, &endpoint.host).await
+    {
+        if !json {
+            group.suspend(|| println!("{} State: {}", CROSS, e));
+        }
+        let mut report = ServiceReport {
+            timed_out: args.wait.map(|_| false),
+            ..ServiceReport::from_health(&HealthReport::unreachable("state", target.as_str(), e))
+        };
+        if let StateTarget::Grpc(_) = target {
+            report.address = report.url.take();
+        }
+        return Ok(report);
+    }
+
+    if !json && let StateTarget::Http(_) = target {
+        warn_token(group, "State", "STATE_AUTH_TOKEN", &token);
+    }
+    let criteria = args.health_criteria();
+    let message = format!("Checking State at {}...", target.as_str());
+    let spinner = check_spinner(json, group, &message);
+    let (health, timed_out) = poll(
+        args.polling(),
+        &spinner,
+        &message,
+        || probe_state_target(&target, &args.http, &client, &token, &criteria),
+        |report| report.healthy,
+    )
+    .await;
+    spinner.finish_and_clear();
+
+    let mut report = ServiceReport {
+        timed_out: args.wait.map(|_| timed_out),
+        ..ServiceReport::from_health(&health)
+    };
+    if let StateTarget::Grpc(_) = target {
+        report.address = report.url.take();
+    }
+    if json {
+        return Ok(report);
+    }
+
+    group.suspend(|| {
+        if timed_out {
+            print_wait_timeout("State", args.wait);
+        }
+        println!("{} {}", if health.healthy { CHECK } else { CROSS }, health);
+    });
+    Ok(report)
+}
+
+/// `check --check-dns`: resolves `host` before any connection attempt, so a
+/// mistyped name fails with a DNS error rather than a generic request error.
+/// The addresses are shown with `-v`.
+async fn resolve_preflight(
+    args: &CheckArgs,
+    json: bool,
+    group: &MultiProgress,
+    host: &str,
+) -> Result<(), String> {
+    let spinner = check_spinner(json, group, &format!("Resolving {}...", host));
+    let result = resolve_host(host, args.http.timeout()).await;
+    spinner.finish_and_clear();
+    match result {
+        Ok(ips) => {
+            let ips: Vec<String> = ips.iter().map(ToString::to_string).collect();
+            debug!("{} resolves to {}", host, ips.join(", "));
+            Ok(())
+        }
+        Err(e) => Err(format!("DNS resolution failed for {} ({})", host, e)),
+    }
+}
+
+/// Checks that the env file can be written, e.g. that it is not a read-only
+/// mounted secret, or, when it is missing, that its directory allows
+/// creating it.
+fn ensure_env_writable(env_path: &Path) -> Result<(), RiceError> {
+    match fs::OpenOptions::new().append(true).open(env_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(env_write_error(env_path, e.into())),
+        Ok(_) => return Ok(()),
+    }
+    let dir = match env_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".rice-cli-write-test.{}", std::process::id()));
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(RiceError::Config(format!(
+            "{} does not exist, so {} cannot be created. Create the directory, or pass --env-file <path> to write the Rice variables elsewhere.",
+            dir.display(),
+            env_path.display()
+        ))),
+        Err(e) => Err(RiceError::Config(format!(
+            "{} cannot be created in {} ({}). Make the directory writable, or pass --env-file <path> to write the Rice variables elsewhere.",
+            env_path.display(),
+            dir.display(),
+            e
+        ))),
+    }
+}
+
+/// Explains a permission error on the env file; other errors pass through.
+fn env_write_error(env_path: &Path, e: RiceError) -> RiceError {
+    match e {
+        RiceError::Io(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem
+            ) =>
+        {
+            RiceError::Config(format!(
+                "{} is not writable ({}). Make it writable with `chmod u+w {}`, or pass --env-file <path> to write the Rice variables elsewhere.",
+                env_path.display(),
+                e,
+                env_path.display()
+            ))
+        }
+        e => e,
+    }
+}
+
+/// Writes a setup file, backing up any existing version first unless
+/// `--no-backup` was given. With `--dry-run`, prints a diff instead.
+fn apply_file(path: &Path, content: &str, args: &SetupArgs) -> Result<(), RiceError> {
+    write_file(path, content, args.dry_run, !args.no_backup)
+}
+
+fn write_file(path: &Path, content: &str, dry_run: bool, backup: bool) -> Result<(), RiceError> {
+    let existing = if path.exists() {
+        Some(fs::read_to_string(path)?)
+    } else {
+        None
+    };
+
+    if dry_run {
+        let action = if existing.is_some() {
+            "update"
+        } else {
+            "create"
+        };
+        println!(
+            "{} Would {} {}",
+            style("[dry-run]").yellow(),
+            action,
+            path.display()
+        );
+        print_diff(existing.as_deref().unwrap_or(""), content);
+        return Ok(());
+    }
+
+    if existing.is_some() && backup {
+        let backup = backup_file(path)?;
+        println!(
+            "{} Backed up {} to {}",
+            CHECK,
+            path.display(),
+            backup.display()
+        );
+    }
+    fs::write(path, content)?;
+    let action = if existing.is_some() {
+        "Updated"
+    } else {
+        "Created"
+    };
+    println!("{} {} {}", CHECK, action, path.display());
+    Ok(())
+}
+
+/// Offers to add the env file and its backups to the `.gitignore` next to
+/// it, creating the `.gitignore` if needed, so secrets are not committed.
+fn ignore_env_file(
+    theme: &ColorfulTheme,
+    args: &SetupArgs,
+    env_path: &Path,
+) -> Result<(), RiceError> {
+    let Some(env_name) = env_path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+    let gitignore_path = env_path.with_file_name(".gitignore");
+    let existing = if gitignore_path.exists() {
+        Some(fs::read_to_string(&gitignore_path)?)
+    } else {
+        None
+    };
+    let content = existing.as_deref().unwrap_or("");
+    if gitignore_ignores(content, env_name) {
+        return Ok(());
+    }
+
+    let prompt = match existing {
+        Some(_) => format!(
+            "{} is not in {}. Add it?",
+            env_name,
+            gitignore_path.display()
+        ),
+        None => format!(
+            "No {} found. Create one that ignores {}?",
+            gitignore_path.display(),
+            env_name
+        ),
+    };
+    // .env holds the auth tokens, so -y keeps it out of version control too.
+    if !ask_confirm(
+        theme,
+        args,
+        &prompt,
+        args.no_gitignore.then_some(false),
+        true,
+    )? {
+        return Ok(());
+    }
+
+    let mut updated = content.to_string();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(&format!("{}\n", env_name));
+    let backups = format!("{}.bak.*", env_name);
+    if !gitignore_ignores(content, &format!("{}.bak.0", env_name)) {
+        updated.push_str(&format!("{}\n", backups));
+    }
+    // A backup of .gitignore would itself show up as untracked.
+    write_file(&gitignore_path, &updated, args.dry_run, false)?;
+    Ok(())
+}
+
+/// Prints the changed lines between `old` and `new` as a `-`/`+` diff. Token
+/// values are masked so previews never reveal secrets.
+fn print_diff(old: &str, new: &str) {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence table; the files involved are small.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            println!("{}", style(format!("- {}", mask_env_line(old[i]))).red());
+            i += 1;
+        } else {
+            println!("{}", style(format!("+ {}", mask_env_line(new[j]))).green());
+            j += 1;
+        }
+    }
+}
+
+fn run_uninstall(yes: bool, global: &GlobalArgs) -> Result<(), RiceError> {
+    say!("{}", style("Removing Rice configuration...").bold());
+
+    let theme = ColorfulTheme::default();
+    let mut found_anything = false;
+
+    let config_paths = match &global.config_file {
+        Some(path) => vec![path.clone()],
+        None => CONFIG_FILE_NAMES
+            .into_iter()
+            .chain([CONFIG_DIR])
+            .map(PathBuf::from)
+            .collect(),
+    };
+    for config_path in config_paths {
+        if !config_path.exists() {
+            continue;
+        }
+        found_anything = true;
+        let remove = yes
+            || Confirm::with_theme(&theme)
+                .with_prompt(format!("Delete {}?", config_path.display()))
+                .default(false)
+                .interact()?;
+
+        if remove && config_path.is_dir() {
+            if remove_config_dir(&config_path)? {
+                println!("{} Removed {}", CHECK, config_path.display());
+            } else {
+                println!(
+                    "{} Removed the Rice fragments from {}, which still holds other files",
+                    CHECK,
+                    config_path.display()
+                );
+            }
+        } else if remove {
+            fs::remove_file(&config_path)?;
+            println!("{} Removed {}", CHECK, config_path.display());
+        } else {
+            println!("{} Kept {}", CHECK, config_path.display());
+        }
+    }
+
+    let env_path = global.env_path();
+    let mut prefixes = vec![global.env_prefix()];
+    found_anything |= strip_env_file(&env_path, |content| strip_rice_env(content, &prefixes[0]))?;
+    // `setup --local` writes .env.local, which uninstall has no flag for.
+    if global.profile.is_none() && global.env_file.is_none() {
+        let local_path = PathBuf::from(LOCAL_ENV_FILE);
+        let local_prefix = global.env_prefix.clone().unwrap_or_else(|| {
+            let content = fs::read_to_string(&local_path).unwrap_or_default();
+            env_prefix(&content).to_string()
+        });
+        found_anything |= strip_env_file(&local_path, |content| {
+            strip_rice_env(content, &local_prefix)
+        })?;
+        if !prefixes.contains(&local_prefix) {
+            prefixes.push(local_prefix);
+        }
+    }
+    let example_path = env_path.with_file_name(".env.example");
+    found_anything |= strip_env_file(&example_path, |content| {
+        prefixes
+            .iter()
+            .fold((content.to_string(), 0), |(content, removed), prefix| {
+                let (remaining, more) = strip_rice_env_example(&content, prefix);
+                (remaining, removed + more)
+            })
+    })?;
+
+    for var in SECRET_ENV_KEYS {
+        if global
+            .keyring_entry(var)
+            .and_then(|entry| entry.delete_credential())
+            .is_ok()
+        {
+            println!("{} Removed {} from the OS keyring", CHECK, var);
+            found_anything = true;
+        }
+    }
+
+    if !found_anything {
+        println!("No Rice configuration found. Nothing to do.");
+    }
+
+    Ok(())
+}
+
+/// Removes the Rice lines from the env file at `path` with `strip`, and the
+/// file itself when nothing else is left. Returns whether it held any.
+fn strip_env_file(
+    path: &Path,
+    strip: impl FnOnce(&str) -> (String, usize),
+) -> Result<bool, RiceError> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let (remaining, removed) = strip(&fs::read_to_string(path)?);
+    if removed == 0 {
+        return Ok(false);
+    }
+    if remaining.is_empty() {
+        fs::remove_file(path)?;
+        println!(
+            "{} Removed {} (it only held Rice variables)",
+            CHECK,
+            path.display()
+        );
+    } else {
+        fs::write(path, remaining)?;
+        println!(
+            "{} Removed {} Rice lines from {}",
+            CHECK,
+            removed,
+            path.display()
+        );
+    }
+    Ok(true)
+}
+
+/// Uninstalls and then runs setup, so nothing from the old configuration
+/// (not even prompt defaults) carries over.
+async fn run_reset(args: SetupArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    if args.dry_run {
+        return Err(RiceError::Config(
+            "reset does not support --dry-run; preview with `setup --dry-run`".into(),
+        ));
+    }
+    let theme = ColorfulTheme::default();
+    let confirmed = ask_confirm(
+        &theme,
+        &args,
+        "Remove the existing Rice configuration and set Rice up again?",
+        (args.non_interactive || args.force).then_some(true),
+        false,
+    )?;
+    if !confirmed {
+        println!("Reset cancelled. Nothing was changed.");
+        return Ok(());
+    }
+
+    run_uninstall(true, global)?;
+    say!();
+    run_setup(args, global).await
+}
+
+/// `check`/`doctor --summary-only` result, printed as one line of text or
+/// one report.
+#[derive(Serialize)]
+struct SummaryReport {
+    schema_version: u32,
+    command: &'static str,
+    ok: bool,
+    /// `ok`, else `unreachable` or `unhealthy` for `check` and `failed` for
+    /// `doctor`
+    status: &'static str,
+    passed: usize,
+    failed: usize,
+    warnings: usize,
+}
+
+impl SummaryReport {
+    fn print(&self, global: &GlobalArgs) -> Result<(), RiceError> {
+        if global.structured() {
+            return global.print_report(self);
+        }
+        println!(
+            "{}: {} ({} passed, {} failed, {} warnings)",
+            self.command, self.status, self.passed, self.failed, self.warnings
+        );
+        Ok(())
+    }
+}
+
+/// Tallies doctor results while printing one line per check, unless only
+/// the summary is wanted.
+#[derive(Default)]
+struct Diagnosis {
+    passed: usize,
+    failed: usize,
+    warnings: usize,
+    summary_only: bool,
+}
+
+impl Diagnosis {
+    fn pass(&mut self, message: impl std::fmt::Display) {
+        self.passed += 1;
+        if !self.summary_only {
+            println!("{} {}", CHECK, message);
+        }
+    }
+
+    fn fail(&mut self, message: impl std::fmt::Display) {
+        self.failed += 1;
+        if !self.summary_only {
+            println!("{} {}", CROSS, style(message).red());
+        }
+    }
+
+    fn warn(&mut self, message: impl std::fmt::Display) {
+        self.warnings += 1;
+        if !self.summary_only {
+            println!("{} {}", WARN, style(message).yellow());
+        }
+    }
+
+    /// Notes checks that were not run; they count as neither passed nor
+    /// failed.
+    fn skip(&self, message: impl std::fmt::Display) {
+        if !self.summary_only {
+            println!("{}", style(format!("-  {}", message)).dim());
+        }
+    }
+
+    fn spinner(&self, message: String) -> ProgressBar {
+        if self.summary_only {
+            ProgressBar::hidden()
+        } else {
+            new_spinner(message)
+        }
+    }
+}
+
+async fn run_doctor(
+    http: HttpArgs,
+    summary_only: bool,
+    global: &GlobalArgs,
+) -> Result<(), RiceError> {
+    if !summary_only {
+        say!("{}", style("Diagnosing Rice setup...").bold());
+    }
+    let mut diagnosis = Diagnosis {
+        summary_only,
+        ..Diagnosis::default()
+    };
+
+    let env_path = global.env_path();
+    let prefix = global.env_prefix();
+    if !env_path.exists()
+        && RICE_ENV_KEYS
+            .iter()
+            .any(|var| std::env::var_os(format!("{}{}", prefix, var)).is_some())
+    {
+        // Platforms such as Heroku and Fly inject variables without a file.
+        diagnosis.pass(format!(
+            "{} not found; using Rice variables from the environment",
+            env_path.display()
+        ));
+    } else if !env_path.exists() {
+        diagnosis.fail(format!(
+            "{} not found. Run `rice-cli setup` to create it.",
+            env_path.display()
+        ));
+    } else {
+        match dotenvy::from_path_iter(&env_path)
+            .and_then(|mut entries| entries.try_for_each(|entry| entry.map(|_| ())))
+        {
+            Ok(()) => diagnosis.pass(format!("{} found and parses", env_path.display())),
+            Err(e) => diagnosis.fail(format!("{} could not be parsed: {}", env_path.display(), e)),
+        }
+    }
+    global.load_env();
+    let (storage_enabled, state_enabled) = enabled_services(global);
+    for (service, enabled) in [("Storage", storage_enabled), ("State", state_enabled)] {
+        if !enabled {
+            diagnosis.skip(format!("{} is disabled; skipping its checks", service));
+        }
+    }
+    // The *_ENABLED flags are checked either way.
+    let checked = |var: &str| {
+        var.ends_with("_ENABLED")
+            || if var.starts_with("STORAGE_") {
+                storage_enabled
+            } else {
+                state_enabled
+            }
+    };
+
+    // STATE_HTTP_PORT is optional; without it State is checked over gRPC.
+    for var in RICE_ENV_KEYS
+        .into_iter()
+        .filter(|&var| var != "STATE_HTTP_PORT" && checked(var))
+    {
+        match global.lookup(var) {
+            Some(val) if !val.is_empty() => diagnosis.pass(format!("{} is set", var)),
+            Some(_) => diagnosis.warn(format!("{} is empty", var)),
+            None => diagnosis.warn(format!("{} is not set", var)),
+        }
+    }
+
+    let storage_url = env_var("STORAGE_INSTANCE_URL").filter(|_| storage_enabled);
+    let state_url = env_var("STATE_INSTANCE_URL").filter(|_| state_enabled);
+    for (var, url, validate) in [
+        (
+            "STORAGE_INSTANCE_URL",
+            &storage_url,
+            validate_instance_url as fn(&str) -> _,
+        ),
+        ("STATE_INSTANCE_URL", &state_url, validate_state_url),
+    ] {
+        if let Some(url) = url {
+            match validate(url) {
+                Ok(_) => diagnosis.pass(format!("{} is a valid address", var)),
+                Err(e) => diagnosis.fail(format!("{} is invalid: {}", var, e)),
+            }
+        }
+    }
+
+    match global.locate_config() {
+        Some((path, format)) => {
+            let name = path.display();
+            match validate_config_path(&path, format) {
+                Ok(Ok(_)) => diagnosis.pass(format!("{} looks valid", name)),
+                Ok(Err(problems)) => {
+                    diagnosis.fail(format!("{} looks malformed: {}", name, problems.join("; ")))
+                }
+                Err(e) => diagnosis.fail(format!("{} could not be read: {}", name, e)),
+            }
+        }
+        None => diagnosis.fail(global.config_not_found()),
+    }
+
+    if let Some(storage_url) = &storage_url {
+        let http_port = env_var("STORAGE_HTTP_PORT").unwrap_or("3000".to_string());
+        let token = global.secret("STORAGE_AUTH_TOKEN");
+        if let Ok(endpoint) = Endpoint::new(storage_url, &http_port) {
+            let health_url = http.health_url(&endpoint);
+            let spinner =
+                diagnosis.spinner(format!("Checking Storage health at {}...", health_url));
+            let client = http.client_for(&endpoint)?;
+            let health = http
+                .check_health(
+                    &client,
+                    "storage",
+                    &health_url,
+                    &token,
+                    &HealthCriteria::default(),
+                )
+                .await;
+            spinner.finish_and_clear();
+            if health.healthy {
+                diagnosis.pass(health)
+            } else {
+                diagnosis.fail(health)
+            }
+        }
+    }
+
+    if let Some(state_url) = &state_url {
+        let http_port = env_var("STATE_HTTP_PORT").unwrap_or_default();
+        match StateTarget::new(state_url, &http_port, http.scheme, &http.health_path()) {
+            Ok(target) => {
+                let token = global.secret("STATE_AUTH_TOKEN");
+                let spinner =
+                    diagnosis.spinner(format!("Checking State at {}...", target.as_str()));
+                let health = probe_state_target(
+                    &target,
+                    &http,
+                    &http.client()?,
+                    &token,
+                    &HealthCriteria::default(),
+                )
+                .await;
+                spinner.finish_and_clear();
+                if health.healthy {
+                    diagnosis.pass(health)
+                } else {
+                    diagnosis.fail(health)
+                }
+            }
+            // An invalid STATE_INSTANCE_URL already failed above.
+            Err(e) if validate_state_url(state_url).is_ok() => diagnosis.fail(e),
+            Err(_) => {}
+        }
+    }
+
+    if summary_only {
+        let ok = diagnosis.failed == 0;
+        SummaryReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            command: "doctor",
+            ok,
+            status: if ok { "ok" } else { "failed" },
+            passed: diagnosis.passed,
+            failed: diagnosis.failed,
+            warnings: diagnosis.warnings,
+        }
+        .print(global)?;
+        return if ok {
+            Ok(())
+        } else {
+            Err(RiceError::Other(String::new()))
+        };
+    }
+    println!(
+        "\n{} passed, {} failed, {} warnings",
+        style(diagnosis.passed).green(),
+        style(diagnosis.failed).red(),
+        style(diagnosis.warnings).yellow()
+    );
+
+    if diagnosis.failed > 0 {
+        return Err(RiceError::Other(format!(
+            "{} checks failed",
+            diagnosis.failed
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the enabled flags from whichever valid Rice config file is present.
+fn read_enabled_flags(global: &GlobalArgs) -> Option<(bool, bool)> {
+    let (path, format) = global.locate_config()?;
+    validate_config_path(&path, format).ok()?.ok()
+}
+
+fn run_validate(global: &GlobalArgs) -> Result<(), RiceError> {
+    let (path, format) = global
+        .locate_config()
+        .ok_or_else(|| RiceError::Config(global.config_not_found()))?;
+    let name = path.display();
+    let result = validate_config_path(&path, format)
+        .map_err(|e| RiceError::Config(format!("Could not read {}: {}", name, e)))?;
+
+    match result {
+        Ok((storage, state)) => {
+            let describe = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+            println!(
+                "{} {} is valid (storage: {}, state: {})",
+                CHECK,
+                name,
+                describe(storage),
+                describe(state)
+            );
+            Ok(())
+        }
+        Err(problems) => {
+            for problem in &problems {
+                println!("{} {}", CROSS, problem);
+            }
+            let plural = if problems.len() == 1 { "" } else { "s" };
+            Err(RiceError::Parse(format!(
+                "{} is invalid ({} problem{})",
+                name,
+                problems.len(),
+                plural
+            )))
+        }
+    }
+}
+
+async fn run_status(http: HttpArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    global.load_env();
+    let structured = global.structured();
+    if !structured {
+        say!("{}", style("Rice Status").bold());
+    }
+
+    let flags_in_env = ["STORAGE_ENABLED", "STATE_ENABLED"]
+        .iter()
+        .any(|var| env_var(var).is_some_and(|value| parse_env_bool(&value).is_some()));
+    let (storage_enabled, state_enabled) = if flags_in_env || read_enabled_flags(global).is_some() {
+        enabled_services(global)
+    } else {
+        if !structured {
+            println!(
+                "{} No valid Rice config file or STORAGE_ENABLED/STATE_ENABLED found; showing both services",
+                WARN
+            );
+        }
+        (true, true)
+    };
+
+    let storage_url = env_var("STORAGE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
+    let state_url = env_var("STATE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
+    let token_hint = |var: &str| {
+        if global.secret(var).is_empty() {
+            "not set"
+        } else {
+            "********"
+        }
+    };
+
+    if !structured {
+        if storage_enabled {
+            println!(
+                "Storage: {} (token: {})",
+                storage_url,
+                token_hint("STORAGE_AUTH_TOKEN")
+            );
+        } else {
+            println!("Storage: {}", style("disabled").dim());
+        }
+        if state_enabled {
+            println!(
+                "State:   {} (token: {})",
+                state_url,
+                token_hint("STATE_AUTH_TOKEN")
+            );
+        } else {
+            println!("State:   {}", style("disabled").dim());
+        }
+    }
+
+    // An invalid address is reported with its service, then fails the run.
+    let mut invalid = None;
+    let mut storage = ServiceReport::disabled("storage");
+    if storage_enabled {
+        let http_port = env_var("STORAGE_HTTP_PORT").unwrap_or("3000".to_string());
+        let token = global.secret("STORAGE_AUTH_TOKEN");
+        let health = match Endpoint::new(&storage_url, &http_port) {
+            Ok(endpoint) => {
+                let health_url = http.health_url(&endpoint);
+                let client = http.client_for(&endpoint)?;
+                http.check_health(
+                    &client,
+                    "storage",
+                    &health_url,
+                    &token,
+                    &HealthCriteria::default(),
+                )
+                .await
+            }
+            Err(e) => {
+                let health = HealthReport::unreachable("storage", &storage_url, e.to_string());
+                invalid.get_or_insert(e);
+                health
+            }
+        };
+        storage = ServiceReport::from_health(&health);
+        if !structured {
+            println!("{} {}", if health.healthy { CHECK } else { CROSS }, health);
+        }
+    }
+
+    let mut state = ServiceReport::disabled("state");
+    if state_enabled {
+        let http_port = env_var("STATE_HTTP_PORT").unwrap_or_default();
+        let health =
+            match StateTarget::new(&state_url, &http_port, http.scheme, &http.health_path()) {
+                Ok(target) => {
+                    let token = global.secret("STATE_AUTH_TOKEN");
+                    let health = probe_state_target(
+                        &target,
+                        &http,
+                        &http.client()?,
+                        &token,
+                        &HealthCriteria::default(),
+                    )
+                    .await;
+                    state = ServiceReport::from_health(&health);
+                    if let StateTarget::Grpc(_) = target {
+                        state.address = state.url.take();
+                    }
+                    health
+                }
+                Err(e) => {
+                    let health = HealthReport::unreachable("state", &state_url, e.to_string());
+                    state = ServiceReport::from_health(&health);
+                    invalid.get_or_insert(e);
+                    health
+                }
+            };
+        if !structured {
+            println!("{} {}", if health.healthy { CHECK } else { CROSS }, health);
+        }
+    }
+
+    let failure = services_failure(&storage, &state);
+    if structured {
+        global.print_report(&CheckReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            services: vec![storage, state],
+        })?;
+    }
+    match invalid {
+        Some(e) => Err(e),
+        None => connectivity_result(failure),
+    }
+}
+
+async fn run_version(http: HttpArgs, global: &GlobalArgs) -> Result<(), RiceError> {
+    global.load_env();
+    let json = global.structured();
+    let cli_version = env!("CARGO_PKG_VERSION");
+
+    let storage_url = env_var("STORAGE_INSTANCE_URL").unwrap_or("localhost:50051".to_string());
+    let http_port = env_var("STORAGE_HTTP_PORT").unwrap_or("3000".to_string());
+    let token = global.secret("STORAGE_AUTH_TOKEN");
+    let endpoint = Endpoint::new(&storage_url, &http_port)
+        .map_err(|e| RiceError::Validation(format!("Invalid STORAGE_INSTANCE_URL: {}", e)))?;
+    let version_url = endpoint.http_health_url(http.scheme, "/version");
+
+    let spinner = check_spinner(
+        json,
+        &spinner_group(),
+        &format!("Fetching server version from {}...", version_url),
+    );
+    let result = health_request(&http.client_for(&endpoint)?, &version_url, &token).await;
+    spinner.finish_and_clear();
+    let res = result.map_err(|e| {
+        RiceError::Unreachable(format!(
+            "Failed to connect to Storage: {}",
+            http.describe(&version_url, e)
+        ))
+    })?;
+
+    let server = if res.status() == reqwest::StatusCode::NOT_FOUND {
+        None
+    } else if res.status().is_success() {
+        let body = res.text().await?;
+        Some(server_version(&body).ok_or_else(|| {
+            RiceError::Parse(format!(
+                "Could not parse a server version from {}",
+                version_url
+            ))
+        })?)
+    } else {
+        return Err(RiceError::Unhealthy(format!(
+            "Unexpected status {} from {}",
+            res.status(),
+            version_url
+        )));
+    };
+
+    let compatible = server
+        .as_deref()
+        .and_then(parse_version)
+        .zip(parse_version(MIN_SERVER_VERSION))
+        .map(|(server, min)| server >= min);
+
+    if json {
+        global.print_report(&VersionReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            cli_version,
+            server_version: server,
+            min_server_version: MIN_SERVER_VERSION,
+            compatible,
+        })?;
+        return Ok(());
+    }
+
+    println!("rice-cli: {}", cli_version);
+    match (&server, compatible) {
+        (Some(server), Some(true)) => println!("{} Rice server: {}", CHECK, server),
+        (Some(server), _) => println!(
+            "{} Rice server {} is older than the minimum supported version {}",
+            WARN, server, MIN_SERVER_VERSION
+        ),
+        (None, _) => println!(
+            "{} Server version info is unavailable ({} not found)",
+            WARN, version_url
+        ),
+    }
+
+    Ok(())
+}
+
+fn run_export(path: &Path, include_secrets: bool, global: &GlobalArgs) -> Result<(), RiceError> {
+    global.load_env();
+    let (storage_enabled, state_enabled) = enabled_services(global);
+    let mut bundle = serde_json::json!({
+        "storage": { "enabled": storage_enabled },
+        "state": { "enabled": state_enabled },
+    });
+    for (var, section, field) in BUNDLE_FIELDS {
+        if SECRET_ENV_KEYS.contains(&var) && !include_secrets {
+            continue;
+        }
+        if let Some(value) = global.lookup(var).filter(|value| !value.is_empty()) {
+            bundle[section][field] = value.into();
+        }
+    }
+
+    fs::write(path, serde_json::to_string_pretty(&bundle)? + "\n")
+        .map_err(|e| RiceError::Other(format!("Could not write {}: {}", path.display(), e)))?;
+    println!(
+        "{} Exported Rice configuration to {}",
+        CHECK,
+        path.display()
+    );
+    if include_secrets {
+        println!(
+            "{} {} contains auth tokens; share it only over a secure channel",
+            WARN,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn run_import(path: &Path, global: &GlobalArgs) -> Result<(), RiceError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| RiceError::Config(format!("Could not read {}: {}", path.display(), e)))?;
+    let bundle: serde_json::Value = serde_json::from_str(&content)
+        .map_err(|e| RiceError::Parse(format!("{} is not valid JSON: {}", path.display(), e)))?;
+    if !bundle.is_object() {
+        return Err(RiceError::Parse(format!(
+            "{} is not a Rice config bundle",
+            path.display()
+        )));
+    }
+
+    let enabled = |section: &str| bundle[section]["enabled"].as_bool().unwrap_or(false);
+    let (storage_enabled, state_enabled) = (enabled("storage"), enabled("state"));
+    if !storage_enabled && !state_enabled {
+        return Err(RiceError::Config(format!(
+            "{} enables neither storage nor state",
+            path.display()
+        )));
+    }
+
+    // Tokens missing from the bundle keep their current .env value, if any.
+    global.load_env();
+    let mut env_values = Vec::new();
+    for var in RICE_ENV_KEYS {
+        let value = match var {
+            "STORAGE_ENABLED" => storage_enabled.to_string(),
+            "STATE_ENABLED" => state_enabled.to_string(),
+            _ => {
+                let (_, section, field) = BUNDLE_FIELDS
+                    .into_iter()
+                    .find(|(key, _, _)| *key == var)
+                    .ok_or_else(|| RiceError::Other(format!("{} has no bundle field", var)))?;
+                match &bundle[section][field] {
+                    serde_json::Value::String(value) => value.clone(),
+                    serde_json::Value::Number(value) => value.to_string(),
+                    _ if SECRET_ENV_KEYS.contains(&var) => {
+                        if global.secret(var).is_empty() && enabled(section) {
+                            println!(
+                                "{} {} has no {}.{}; set {} in {} before connecting",
+                                WARN,
+                                path.display(),
+                                section,
+                                field,
+                                var,
+                                global.env_path().display()
+                            );
+                        }
+                        env_var(var).unwrap_or_default()
+                    }
+                    _ => default_env_value(var).to_string(),
+                }
+            }
+        };
+        validate_env_value(var, &value)
+            .map_err(|e| RiceError::Validation(format!("Invalid {}: {}", var, e)))?;
+        env_values.push((var, value));
+    }
+
+    let config_path = global
+        .config_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(ConfigFormat::default().file_name()));
+    let config_format = ConfigFormat::from_path(&config_path);
+    let env_values: Vec<(&str, &str)> = env_values
+        .iter()
+        .map(|(var, value)| (*var, value.as_str()))
+        .collect();
+    let config_content = config_format.render(
+        ModuleFormat::detect(&config_path),
+        storage_enabled,
+        state_enabled,
+        &env_values,
+    );
+    write_file(&config_path, &config_content, false, true)?;
+
+    // A TOML config file holds the connection values itself, but not the tokens.
+    let env_values = config_format.env_file_values(&env_values);
+    if !env_values.is_empty() {
+        let env_path = global.env_path();
+        let existing_env = if env_path.exists() {
+            fs::read_to_string(&env_path)?
+        } else {
+            String::new()
+        };
+        write_file(
+            &env_path,
+            &merge_env(&existing_env, &global.env_prefix(), &env_values),
+            false,
+            true,
+        )?;
+    }
+
+    println!(
+        "{} Imported Rice configuration from {}",
+        CHECK,
+        path.display()
+    );
     Ok(())
 }
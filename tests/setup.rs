@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// An empty project directory, which also serves as `HOME` so no `.ricerc`
+/// is picked up.
+fn project_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rice-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn rice_cli(dir: &Path, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rice-cli"));
+    for (var, _) in std::env::vars() {
+        if var.starts_with("STORAGE_") || var.starts_with("STATE_") || var.starts_with("RICE_") {
+            command.env_remove(var);
+        }
+    }
+    command
+        .env("HOME", dir)
+        .arg("--cwd")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn non_interactive_local_template_needs_no_token() {
+    let dir = project_dir("template-local");
+    let output = rice_cli(&dir, &["setup", "-y", "--template", "local", "--no-verify"]);
+    assert!(output.status.success(), "{:?}", output);
+    let env = std::fs::read_to_string(dir.join(".env")).unwrap();
+    assert!(env.contains("STORAGE_AUTH_TOKEN=\n"), "{}", env);
+    assert!(dir.join("rice.config.js").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn non_interactive_setup_without_token_exits_5() {
+    let dir = project_dir("no-token");
+    let output = rice_cli(&dir, &["setup", "-y", "--enable-storage", "--no-verify"]);
+    assert_eq!(output.status.code(), Some(5), "{:?}", output);
+    assert!(!dir.join(".env").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}